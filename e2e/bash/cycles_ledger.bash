@@ -0,0 +1,98 @@
+#!/usr/bin/env bats
+
+# shellcheck source=/dev/null
+source "$BATS_SUPPORT/load.bash"
+
+load util/assertions
+
+CYCLES_LEDGER_ID="um5iw-rqaaa-aaaaq-qaaba-cai"
+
+setup() {
+    if [ -z "${CYCLES_LEDGER_WASM:-}" ]; then
+        skip "set CYCLES_LEDGER_WASM to a cycles ledger wasm to run these tests"
+    fi
+
+    # We want to work from a temporary directory, different for every test.
+    x=$(mktemp -d -t dfx-usage-env-home-XXXXXXXX)
+    cd "$x" || exit
+    export DFX_CONFIG_ROOT=$x
+
+    dfx new --no-frontend e2e_project
+    cd e2e_project || exit 1
+
+    cat > cycles_ledger.did <<EOF
+type LedgerArgs = variant { Init: record { max_blocks_per_request: nat64; index_id: opt principal } };
+service : (LedgerArgs) -> {}
+EOF
+    cat > dfx.json <<EOF
+{
+  "canisters": {
+    "cycles_ledger": {
+      "type": "custom",
+      "candid": "cycles_ledger.did",
+      "wasm": "${CYCLES_LEDGER_WASM}"
+    }
+  },
+  "version": 1
+}
+EOF
+    dfx start --background --clean
+    dfx deploy cycles_ledger --specified-id "${CYCLES_LEDGER_ID}" --argument '(variant { Init = record { max_blocks_per_request = 1000; index_id = null } })'
+}
+
+teardown() {
+    if [ -n "${DFX_CONFIG_ROOT:-}" ]; then
+        dfx stop
+        rm -rf "$DFX_CONFIG_ROOT"
+    fi
+}
+
+@test "deposit into and withdraw from the cycles ledger" {
+    WALLET=$(dfx identity get-wallet)
+
+    assert_command dfx canister call "${WALLET}" get_cycles_ledger
+    assert_match "${CYCLES_LEDGER_ID}"
+
+    assert_command dfx canister call "${WALLET}" wallet_cycles_ledger_deposit '(record { amount = 1_000_000_000_000 : nat })'
+    assert_match "Ok"
+
+    assert_command dfx canister call "${WALLET}" wallet_cycles_ledger_balance '(null)'
+    assert_match "Ok = 999_900_000_000"
+
+    assert_command dfx canister call "${WALLET}" wallet_cycles_ledger_withdraw '(record { amount = 500_000_000_000 : nat })'
+    assert_match "Ok"
+
+    assert_command dfx canister call "${WALLET}" wallet_cycles_ledger_balance '(null)'
+    assert_match "Ok = 499_800_000_000"
+
+    assert_command dfx canister call "${WALLET}" get_events128 '(opt record { kinds = opt vec { "CyclesLedgerDeposit"; "CyclesLedgerWithdrawal" } })'
+    assert_match "CyclesLedgerDeposit"
+    assert_match "CyclesLedgerWithdrawal"
+}
+
+@test "non-controllers cannot approve cycles ledger allowances" {
+    WALLET=$(dfx identity get-wallet)
+    dfx identity new alice
+    ALICE=$(dfx --identity alice identity get-principal)
+    assert_command dfx canister call "${WALLET}" authorize "(principal \"${ALICE}\")"
+    # Approvals pay the ledger fee.
+    assert_command dfx canister call "${WALLET}" wallet_cycles_ledger_deposit '(record { amount = 1_000_000_000 : nat })'
+
+    assert_command_fail dfx --identity alice canister call "${WALLET}" wallet_cycles_ledger_approve "(record { spender = record { owner = principal \"${ALICE}\" }; amount = 1_000 : nat })"
+    assert_command dfx canister call "${WALLET}" wallet_cycles_ledger_approve "(record { spender = record { owner = principal \"${ALICE}\" }; amount = 1_000 : nat })"
+    assert_match "Ok"
+}
+
+@test "call policies restrict deposits into other accounts" {
+    WALLET=$(dfx identity get-wallet)
+    dfx identity new alice
+    ALICE=$(dfx --identity alice identity get-principal)
+    assert_command dfx canister call "${WALLET}" authorize "(principal \"${ALICE}\")"
+    assert_command dfx canister call "${WALLET}" set_call_policy "(principal \"${ALICE}\", opt record { allowed = vec { record { canister = opt principal \"${CYCLES_LEDGER_ID}\"; methods = vec { \"*\" } } } })"
+
+    assert_command dfx --identity alice canister call "${WALLET}" wallet_cycles_ledger_deposit "(record { to = opt record { owner = principal \"${ALICE}\" }; amount = 1_000_000_000 : nat })"
+    assert_match "does not allow calling \`deposit_cycles\`"
+
+    assert_command dfx --identity alice canister call "${WALLET}" wallet_cycles_ledger_deposit '(record { amount = 1_000_000_000 : nat })'
+    assert_match "Ok"
+}
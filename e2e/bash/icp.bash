@@ -0,0 +1,56 @@
+#!/usr/bin/env bats
+
+# shellcheck source=/dev/null
+source "$BATS_SUPPORT/load.bash"
+
+load util/assertions
+
+ICP_LEDGER_ID="ryjl3-tyaaa-aaaaa-aaaba-cai"
+CYCLES_MINTING_CANISTER_ID="rkp4c-7iaaa-aaaaa-aaaaq-cai"
+
+setup() {
+    # We want to work from a temporary directory, different for every test.
+    x=$(mktemp -d -t dfx-usage-env-home-XXXXXXXX)
+    cd "$x" || exit
+    export DFX_CONFIG_ROOT=$x
+
+    dfx new --no-frontend e2e_project
+    cd e2e_project || exit 1
+    dfx start --background
+}
+
+teardown() {
+    dfx stop
+    rm -rf "$DFX_CONFIG_ROOT"
+}
+
+@test "controllers can change the ICP canisters" {
+    WALLET=$(dfx identity get-wallet)
+
+    assert_command dfx canister call "${WALLET}" get_icp_canisters
+    assert_match "${ICP_LEDGER_ID}"
+    assert_match "${CYCLES_MINTING_CANISTER_ID}"
+
+    assert_command dfx canister call "${WALLET}" set_icp_canisters "(opt record { ledger = principal \"${WALLET}\"; cycles_minting_canister = principal \"${WALLET}\" })"
+    assert_match "Ok"
+    assert_command dfx canister call "${WALLET}" get_icp_canisters
+    assert_match "ledger = principal \"${WALLET}\""
+
+    # The wallet is no ICP ledger, so converting ICP fails calling it.
+    assert_command dfx canister call "${WALLET}" wallet_top_up_with_icp '(record { amount_e8s = 100_000_000 : nat64 })'
+    assert_match "An error happened during the call to the ICP ledger"
+
+    assert_command dfx canister call "${WALLET}" set_icp_canisters '(null)'
+    assert_match "Ok"
+    assert_command dfx canister call "${WALLET}" get_icp_canisters
+    assert_match "${ICP_LEDGER_ID}"
+}
+
+@test "non-controllers cannot change the ICP canisters" {
+    WALLET=$(dfx identity get-wallet)
+    dfx identity new alice
+    ALICE=$(dfx --identity alice identity get-principal)
+    assert_command dfx canister call "${WALLET}" authorize "(principal \"${ALICE}\")"
+
+    assert_command_fail dfx --identity alice canister call "${WALLET}" set_icp_canisters '(null)'
+}
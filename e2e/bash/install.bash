@@ -0,0 +1,70 @@
+#!/usr/bin/env bats
+
+# shellcheck source=/dev/null
+source "$BATS_SUPPORT/load.bash"
+
+load util/assertions
+
+setup() {
+    # We want to work from a temporary directory, different for every test.
+    x=$(mktemp -d -t dfx-usage-env-home-XXXXXXXX)
+    cd "$x" || exit
+    export DFX_CONFIG_ROOT=$x
+
+    dfx new --no-frontend e2e_project
+    cd e2e_project || exit 1
+    dfx start --background
+}
+
+teardown() {
+    dfx stop
+    rm -rf "$DFX_CONFIG_ROOT"
+}
+
+# The contents of a file as a candid blob literal.
+blob_of() {
+    printf 'blob "%s"' "$(xxd -p "$1" | tr -d '\n' | sed 's/../\\&/g')"
+}
+
+# The SHA-256 of a file as a candid blob literal.
+hash_of() {
+    printf 'blob "%s"' "$(sha256sum "$1" | cut -d ' ' -f 1 | sed 's/../\\&/g')"
+}
+
+@test "install code from uploaded chunks" {
+    # invokes:
+    #  - wallet_upload_chunk
+    #  - wallet_install_code
+    #  - wallet_clear_chunk_store
+    WALLET=$(dfx identity get-wallet)
+    dfx canister create e2e_project
+    dfx build e2e_project
+    CANISTER=$(dfx canister id e2e_project)
+    WASM=.dfx/local/canisters/e2e_project/e2e_project.wasm
+
+    split -b 100000 -d "$WASM" chunk.
+    CHUNK_HASHES=""
+    for CHUNK in chunk.*; do
+        assert_command dfx canister call "$WALLET" wallet_upload_chunk "(record { canister = principal \"$CANISTER\"; chunk = $(blob_of "$CHUNK") })"
+        assert_match "Ok"
+        CHUNK_HASHES="$CHUNK_HASHES $(hash_of "$CHUNK");"
+    done
+
+    # The argument is an empty candid message.
+    INSTALL_ARGS="canister = principal \"$CANISTER\"; chunk_hashes = opt vec {$CHUNK_HASHES }; wasm_module_hash = opt $(hash_of "$WASM"); arg = blob \"DIDL\\00\\00\""
+    assert_command dfx canister call "$WALLET" wallet_install_code "(record { $INSTALL_ARGS; mode = variant { install } })"
+    assert_match "Ok"
+
+    assert_command dfx canister call e2e_project greet '("wallet")'
+    assert_match "Hello, wallet!"
+
+    # CodeInstalled=3813038893
+    assert_command dfx canister call "$WALLET" get_managed_canister_events "(record { canister = principal \"$CANISTER\" })"
+    assert_match "(CodeInstalled|3_813_038_893)"
+
+    # Without the chunks, the module cannot be installed again.
+    assert_command dfx canister call "$WALLET" wallet_clear_chunk_store "(principal \"$CANISTER\")"
+    assert_match "Ok"
+    assert_command dfx canister call "$WALLET" wallet_install_code "(record { $INSTALL_ARGS; mode = variant { reinstall } })"
+    assert_match "Err"
+}
@@ -0,0 +1,143 @@
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+/// The number of requests that can be pending at the same time. Once that many are, nobody can
+/// request access until some are handled or expire, however many principals fill the queue.
+const MAX_PENDING_REQUESTS: usize = 50;
+/// How long a request stays pending before it is dropped, in nanoseconds.
+const REQUEST_TTL: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+/// The longest message a request can carry, in characters.
+const MAX_MESSAGE_LENGTH: usize = 256;
+/// How long a principal has to wait between two requests, in nanoseconds.
+const REQUEST_INTERVAL: u64 = 60 * 60 * 1_000_000_000;
+
+/// A request of a principal to become a custodian of the wallet.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AccessRequest {
+    pub id: u64,
+    pub requester: Principal,
+    pub message: Option<String>,
+    pub requested_at: u64,
+}
+
+/// The pending access requests, and when each principal last made one.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct AccessRequests {
+    next_id: u64,
+    pending: BTreeMap<u64, AccessRequest>,
+    last_requested: BTreeMap<Principal, u64>,
+}
+
+thread_local! {
+    pub static ACCESS_REQUESTS: RefCell<AccessRequests> = Default::default();
+}
+
+impl AccessRequest {
+    fn has_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.requested_at) >= REQUEST_TTL
+    }
+}
+
+impl AccessRequests {
+    /// Queue a request of `requester`, unless they made one too recently or too many are pending.
+    pub fn request(
+        &mut self,
+        requester: Principal,
+        message: Option<String>,
+        now: u64,
+    ) -> Result<u64, String> {
+        if matches!(&message, Some(message) if message.chars().count() > MAX_MESSAGE_LENGTH) {
+            return Err(format!(
+                "The message cannot be longer than {} characters.",
+                MAX_MESSAGE_LENGTH
+            ));
+        }
+        self.pending.retain(|_, request| !request.has_expired(now));
+        if self.pending.values().any(|r| r.requester == requester) {
+            return Err("You already have a pending access request.".to_string());
+        }
+        // Forget the principals that are free to ask again, so this doesn't grow forever.
+        self.last_requested
+            .retain(|_, at| now.saturating_sub(*at) < REQUEST_INTERVAL);
+        if self.last_requested.contains_key(&requester) {
+            return Err(format!(
+                "You can only request access once every {} minutes.",
+                REQUEST_INTERVAL / 60_000_000_000
+            ));
+        }
+        if self.pending.len() >= MAX_PENDING_REQUESTS {
+            return Err("Too many access requests are pending. Try again later.".to_string());
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(
+            id,
+            AccessRequest {
+                id,
+                requester,
+                message,
+                requested_at: now,
+            },
+        );
+        self.last_requested.insert(requester, now);
+        Ok(id)
+    }
+
+    /// Remove a pending request, to approve or deny it.
+    pub fn take(&mut self, id: u64, now: u64) -> Result<AccessRequest, String> {
+        self.pending
+            .remove(&id)
+            .filter(|request| !request.has_expired(now))
+            .ok_or_else(|| format!("No pending access request with ID {}.", id))
+    }
+
+    /// The requests that haven't expired at `now`.
+    pub fn list(&self, now: u64) -> Vec<AccessRequest> {
+        self.pending
+            .values()
+            .filter(|request| !request.has_expired(now))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AccessRequests, MAX_PENDING_REQUESTS, REQUEST_INTERVAL, REQUEST_TTL};
+    use candid::Principal;
+
+    #[test]
+    fn rate_limits_requests() {
+        let alice = Principal::anonymous();
+        let mut requests = AccessRequests::default();
+        let id = requests.request(alice, None, 0).unwrap();
+        assert!(requests.request(alice, None, 1).is_err());
+
+        requests.take(id, 1).unwrap();
+        assert!(requests.take(id, 1).is_err());
+        assert!(requests.request(alice, None, REQUEST_INTERVAL - 1).is_err());
+        requests
+            .request(alice, Some("CI".to_string()), REQUEST_INTERVAL)
+            .unwrap();
+        assert_eq!(requests.list(REQUEST_INTERVAL).len(), 1);
+    }
+
+    #[test]
+    fn expires_requests() {
+        let mut requests = AccessRequests::default();
+        for i in 0..MAX_PENDING_REQUESTS {
+            requests
+                .request(Principal::from_slice(&[i as u8]), None, 0)
+                .unwrap();
+        }
+        let late = Principal::from_slice(&[0xff]);
+        assert!(requests.request(late, None, 1).is_err());
+        assert!(requests.take(0, REQUEST_TTL).is_err());
+        assert!(requests.list(REQUEST_TTL).is_empty());
+        requests.request(late, None, REQUEST_TTL).unwrap();
+        assert_eq!(requests.list(REQUEST_TTL).len(), 1);
+    }
+}
@@ -1,14 +1,15 @@
+use crate::memory::{self, Memory};
 use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
 use serde::Deserialize;
 use std::cell::RefCell;
 use std::cmp::Ordering;
-use std::collections::BTreeSet;
 use std::fmt::Formatter;
 
-/// The role of the address, whether it's a [Contact], [Custodian], or a [Controller]. A
+/// The role of the address, whether it's a [Contact], [Viewer], [Custodian], or a [Controller]. A
 /// [Controller] is the most privileged role, and can rename the wallet, add entries to the
 /// address book. A [Custodian] can access the wallet information, send cycles, forward
-/// calls, and create canisters.
+/// calls, and create canisters. A [Viewer] can access the wallet information, but not change it.
 ///
 /// A [Contact] is simply a way to name canisters, and can be seen as a crude address book.
 ///
@@ -16,6 +17,7 @@ use std::fmt::Formatter;
 #[derive(Clone, Debug, Ord, PartialOrd, Eq, PartialEq, CandidType, Deserialize)]
 pub enum Role {
     Contact,
+    Viewer,
     Custodian,
     Controller,
 }
@@ -39,6 +41,66 @@ pub struct AddressEntry {
     pub kind: Kind,
     /// The role this address has on the wallet.
     pub role: Role,
+    /// What this address may do with the wallet's cycles while it is a [Custodian]. `None` means
+    /// anything.
+    pub call_policy: Option<CallPolicy>,
+    /// When this address stops being a [Custodian], in nanoseconds since the epoch. `None` means
+    /// never.
+    pub expires_at: Option<u64>,
+}
+
+/// A canister, or any canister, and the methods of it a custodian may call.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct CallPermission {
+    /// The canister, or `None` for any canister.
+    pub canister: Option<Principal>,
+    /// Method names, in which `*` matches any sequence of characters.
+    pub methods: Vec<String>,
+}
+
+/// The calls a custodian is restricted to.
+///
+/// Sending cycles to a canister counts as calling its `deposit_cycles` method, and creating a
+/// canister as calling `create_canister` on the management canister.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub struct CallPolicy {
+    pub allowed: Vec<CallPermission>,
+}
+
+impl CallPolicy {
+    pub fn allows(&self, canister: &Principal, method: &str) -> bool {
+        self.allowed.iter().any(|permission| {
+            permission
+                .canister
+                .map_or(true, |allowed| &allowed == canister)
+                && permission
+                    .methods
+                    .iter()
+                    .any(|pattern| matches_pattern(pattern, method))
+        })
+    }
+}
+
+/// Whether `name` matches `pattern`, in which `*` matches any sequence of characters.
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let Some((last, middle)) = parts.split_last() else {
+        // No wildcard: the whole name must match.
+        return rest.is_empty();
+    };
+    for part in middle {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
 }
 
 impl PartialOrd for AddressEntry {
@@ -66,6 +128,8 @@ impl AddressEntry {
             name,
             role,
             kind: Kind::Unknown,
+            call_policy: None,
+            expires_at: None,
         }
     }
 
@@ -80,11 +144,28 @@ impl AddressEntry {
     pub fn is_controller_or_custodian(&self) -> bool {
         self.role == Role::Controller || self.role == Role::Custodian
     }
+
+    pub fn is_viewer_custodian_or_controller(&self) -> bool {
+        self.role >= Role::Viewer
+    }
+
+    /// Whether this is a [Custodian] whose grant expired at or before `now`.
+    pub fn has_lapsed(&self, now: u64) -> bool {
+        self.is_custodian()
+            && self
+                .expires_at
+                .map_or(false, |expires_at| expires_at <= now)
+    }
 }
 
-/// The address book for this wallet.
-#[derive(Default, Clone)]
-pub struct AddressBook(BTreeSet<AddressEntry>);
+/// The address book for this wallet, kept in stable memory.
+pub struct AddressBook(StableBTreeMap<Principal, AddressEntry, Memory>);
+
+impl Default for AddressBook {
+    fn default() -> Self {
+        Self(StableBTreeMap::init(memory::get(memory::ADDRESS_BOOK)))
+    }
+}
 
 thread_local! {
     pub static ADDRESS_BOOK: RefCell<AddressBook> = Default::default();
@@ -93,39 +174,90 @@ thread_local! {
 impl AddressBook {
     #[inline]
     pub fn insert(&mut self, entry: AddressEntry) {
-        if let Some(mut existing) = self.0.take(&entry) {
+        if let Some(mut existing) = self.0.get(&entry.id) {
             if entry.name.is_some() {
                 existing.name = entry.name;
             }
+            // The expiry of a custodian grant is only changed by `set_expiry`.
             if entry.role > existing.role {
                 existing.role = entry.role;
             }
             if !matches!(entry.kind, Kind::Unknown) {
                 existing.kind = entry.kind;
             }
-            self.0.insert(existing);
+            if entry.call_policy.is_some() {
+                existing.call_policy = entry.call_policy;
+            }
+            self.0.insert(existing.id, existing);
         } else {
-            self.0.insert(entry);
+            self.0.insert(entry.id, entry);
         }
     }
 
     #[inline]
-    pub fn find(&self, id: &Principal) -> Option<&AddressEntry> {
-        self.0.iter().find(|&a| &a.id == id)
+    pub fn find(&self, id: &Principal) -> Option<AddressEntry> {
+        self.0.get(id)
+    }
+
+    /// Set or clear when the custodian grant of `principal` lapses. Does nothing to other roles.
+    pub fn set_expiry(&mut self, principal: &Principal, expires_at: Option<u64>) {
+        if let Some(mut entry) = self.find(principal).filter(|e| e.is_custodian()) {
+            entry.expires_at = expires_at;
+            self.0.insert(entry.id, entry);
+        }
+    }
+
+    /// Set or remove the call policy of an address that is in the book.
+    pub fn set_call_policy(
+        &mut self,
+        principal: &Principal,
+        policy: Option<CallPolicy>,
+    ) -> Result<(), String> {
+        let mut entry = self
+            .find(principal)
+            .ok_or_else(|| format!("{} is not in the address book.", principal.to_text()))?;
+        entry.call_policy = policy;
+        self.0.insert(entry.id, entry);
+        Ok(())
+    }
+
+    /// Whether `principal` may call `method` on `canister` through the wallet.
+    pub fn may_call(&self, principal: &Principal, canister: &Principal, method: &str) -> bool {
+        match self.find(principal) {
+            Some(AddressEntry {
+                role: Role::Custodian,
+                call_policy: Some(policy),
+                ..
+            }) => policy.allows(canister, method),
+            _ => true,
+        }
+    }
+
+    /// Whether `principal` is a custodian whose grant expired at or before `now`.
+    pub fn has_lapsed(&self, principal: &Principal, now: u64) -> bool {
+        self.find(principal).map_or(false, |e| e.has_lapsed(now))
+    }
+
+    /// Demote the custodians whose grant expired at or before `now` to contacts, returning them.
+    pub fn demote_lapsed(&mut self, now: u64) -> Vec<AddressEntry> {
+        let lapsed: Vec<AddressEntry> = self.iter().filter(|e| e.has_lapsed(now)).collect();
+        for entry in &lapsed {
+            let mut demoted = entry.clone();
+            demoted.role = Role::Contact;
+            demoted.expires_at = None;
+            self.0.insert(demoted.id, demoted);
+        }
+        lapsed
     }
 
     #[inline]
     pub fn remove(&mut self, principal: &Principal) {
-        // Because we order by ID, we can create entries and remove them.
-        self.0
-            .remove(&AddressEntry::new(*principal, None, Role::Contact));
+        self.0.remove(principal);
     }
 
     #[inline]
     pub fn take(&mut self, principal: &Principal) -> Option<AddressEntry> {
-        // Because we order by ID, we can create entries and remove them.
-        self.0
-            .take(&AddressEntry::new(*principal, None, Role::Contact))
+        self.0.remove(principal)
     }
 
     #[inline]
@@ -145,32 +277,105 @@ impl AddressBook {
     }
 
     #[inline]
-    pub fn custodians(&self) -> impl Iterator<Item = &AddressEntry> {
+    pub fn is_viewer_custodian_or_controller(&self, principal: &Principal) -> bool {
+        self.find(principal)
+            .map_or(false, |e| e.is_viewer_custodian_or_controller())
+    }
+
+    #[inline]
+    pub fn custodians(&self) -> impl Iterator<Item = AddressEntry> + '_ {
         self.iter().filter(|e| e.is_custodian())
     }
 
     #[inline]
-    pub fn controllers(&self) -> impl Iterator<Item = &AddressEntry> {
+    pub fn controllers(&self) -> impl Iterator<Item = AddressEntry> + '_ {
         self.iter().filter(|e| e.is_controller())
     }
 
     #[inline]
-    pub fn iter(&self) -> impl Iterator<Item = &AddressEntry> {
-        self.0.iter()
+    pub fn iter(&self) -> impl Iterator<Item = AddressEntry> + '_ {
+        self.0.values()
     }
 }
 
 impl std::fmt::Debug for AddressBook {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.debug_tuple("AddressBook").field(&self.0).finish()
+        f.debug_list().entries(self.iter()).finish()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::address::{AddressBook, AddressEntry, Role};
+    use crate::address::{AddressBook, AddressEntry, CallPermission, CallPolicy, Role};
     use candid::Principal;
 
+    #[test]
+    fn enforces_call_policies() {
+        let custodian = Principal::anonymous();
+        let canister = Principal::management_canister();
+        let mut book: AddressBook = Default::default();
+        book.insert(AddressEntry::new(custodian, None, Role::Custodian));
+        assert!(book.may_call(&custodian, &canister, "install_code"));
+
+        let policy = CallPolicy {
+            allowed: vec![CallPermission {
+                canister: Some(canister),
+                methods: vec!["icrc1_*".to_string(), "get_*_fee".to_string()],
+            }],
+        };
+        book.set_call_policy(&custodian, Some(policy)).unwrap();
+        assert!(book.may_call(&custodian, &canister, "icrc1_transfer"));
+        assert!(book.may_call(&custodian, &canister, "get_transfer_fee"));
+        assert!(!book.may_call(&custodian, &canister, "get_fee_rate"));
+        assert!(!book.may_call(&custodian, &canister, "install_code"));
+        assert!(!book.may_call(&custodian, &Principal::from_slice(&[1]), "icrc1_transfer"));
+
+        // Controllers are never restricted.
+        book.insert(AddressEntry::new(custodian, None, Role::Controller));
+        assert!(book.may_call(&custodian, &canister, "install_code"));
+    }
+
+    #[test]
+    fn viewers_can_only_read() {
+        let mut book: AddressBook = Default::default();
+        book.insert(AddressEntry::new(
+            Principal::anonymous(),
+            None,
+            Role::Viewer,
+        ));
+        assert!(book.is_viewer_custodian_or_controller(&Principal::anonymous()));
+        assert!(!book.is_controller_or_custodian(&Principal::anonymous()));
+    }
+
+    #[test]
+    fn demotes_lapsed_custodians() {
+        let custodian = Principal::anonymous();
+        let mut book: AddressBook = Default::default();
+        let mut entry = AddressEntry::new(custodian, None, Role::Custodian);
+        entry.expires_at = Some(100);
+        book.insert(entry);
+        assert!(!book.has_lapsed(&custodian, 99));
+        assert!(book.demote_lapsed(99).is_empty());
+        assert!(book.has_lapsed(&custodian, 100));
+
+        let lapsed = book.demote_lapsed(100);
+        assert_eq!(lapsed.len(), 1);
+        assert_eq!(book.find(&custodian).unwrap().role, Role::Contact);
+        assert!(!book.has_lapsed(&custodian, 100));
+
+        // Adding the address again leaves the expiry alone; only a new grant replaces it.
+        book.insert(AddressEntry::new(custodian, None, Role::Custodian));
+        book.set_expiry(&custodian, Some(200));
+        book.insert(AddressEntry::new(
+            custodian,
+            Some("alice".to_string()),
+            Role::Custodian,
+        ));
+        assert!(book.has_lapsed(&custodian, 200));
+        book.set_expiry(&custodian, None);
+        assert!(!book.has_lapsed(&custodian, u64::MAX));
+    }
+
     #[test]
     fn can_update_existing() {
         let mut book: AddressBook = Default::default();
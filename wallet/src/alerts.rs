@@ -0,0 +1,267 @@
+use crate::events::{self, EventKind};
+use crate::status;
+use candid::{CandidType, Principal};
+use ic_cdk::api;
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How often balances with an alert threshold are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(15 * 60);
+/// How many balances are checked at once.
+const CHECK_BATCH_SIZE: usize = 10;
+/// The name events recorded by the alert timer are attributed to.
+const TIMER_METHOD: &str = "timer:balance_alert";
+
+/// When to alert about a low balance.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct AlertThreshold {
+    /// Alert once the balance falls below this many cycles.
+    pub below: u128,
+    /// Don't alert again until the balance went back to at least this many cycles. Defaults to
+    /// `below` plus 10%, so a balance hovering around `below` doesn't alert over and over.
+    pub recover_above: Option<u128>,
+}
+
+impl AlertThreshold {
+    fn recover_above(&self) -> u128 {
+        self.recover_above
+            .unwrap_or_else(|| self.below.saturating_add(self.below / 10))
+    }
+}
+
+/// The canister method alerts are sent to. It is called with a single [`BalanceAlert`], as a one-way
+/// call, so a notifier that doesn't reply can't hold up the checks.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Notifier {
+    pub canister: Principal,
+    pub method: String,
+}
+
+/// The alert sent to the notifier.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct BalanceAlert {
+    pub wallet: Principal,
+    /// The wallet itself, or one of its managed canisters.
+    pub canister: Principal,
+    pub balance: u128,
+    pub threshold: u128,
+    pub timestamp: u64,
+}
+
+/// An alert threshold along with what the timer last saw, as reported to the wallet API.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct AlertStatus {
+    pub canister: Principal,
+    pub threshold: AlertThreshold,
+    /// Whether the balance is below the threshold and was alerted about.
+    pub alerted: bool,
+    pub last_balance: Option<u128>,
+    pub last_checked: Option<u64>,
+    /// The error of the last check or alert, if it failed.
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Alert {
+    threshold: AlertThreshold,
+    alerted: bool,
+    last_balance: Option<u128>,
+    last_checked: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl Alert {
+    /// Whether a balance of `balance` calls for an alert. Call [`Alert::sent`] once it is delivered.
+    fn should_alert(&mut self, balance: u128) -> bool {
+        if self.alerted && balance >= self.threshold.recover_above() {
+            self.alerted = false;
+        }
+        !self.alerted && balance < self.threshold.below
+    }
+
+    fn sent(&mut self) {
+        self.alerted = true;
+    }
+}
+
+/// The alert thresholds of the wallet and its managed canisters, and where alerts go.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Alerts {
+    notifier: Option<Notifier>,
+    alerts: BTreeMap<Principal, Alert>,
+}
+
+thread_local! {
+    pub static ALERTS: RefCell<Alerts> = Default::default();
+    /// Whether the timer is still going through the balances, so runs don't overlap.
+    static RUNNING: Cell<bool> = Cell::new(false);
+}
+
+impl Alerts {
+    pub fn set_notifier(&mut self, notifier: Option<Notifier>) {
+        self.notifier = notifier;
+    }
+
+    pub fn notifier(&self) -> Option<Notifier> {
+        self.notifier.clone()
+    }
+
+    pub fn set(&mut self, canister: Principal, threshold: Option<AlertThreshold>) {
+        match threshold {
+            Some(threshold) => {
+                self.alerts
+                    .entry(canister)
+                    .and_modify(|alert| alert.threshold = threshold.clone())
+                    .or_insert(Alert {
+                        threshold,
+                        alerted: false,
+                        last_balance: None,
+                        last_checked: None,
+                        last_error: None,
+                    });
+            }
+            None => {
+                self.alerts.remove(&canister);
+            }
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<AlertStatus> {
+        self.alerts
+            .iter()
+            .map(|(canister, alert)| AlertStatus {
+                canister: *canister,
+                threshold: alert.threshold.clone(),
+                alerted: alert.alerted,
+                last_balance: alert.last_balance,
+                last_checked: alert.last_checked,
+                last_error: alert.last_error.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Check that a threshold makes sense.
+pub fn validate(threshold: &AlertThreshold) -> Result<(), String> {
+    if threshold.recover_above() < threshold.below {
+        Err("The balance to recover above cannot be below the alert threshold.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Start checking the balances with an alert threshold periodically.
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || ic_cdk::spawn(check_all()));
+}
+
+/// Clears [`RUNNING`] when a run ends, even if one of its calls trapped.
+struct RunGuard;
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(false));
+    }
+}
+
+async fn check_all() {
+    let Some(notifier) = ALERTS.with(|alerts| alerts.borrow().notifier()) else {
+        return;
+    };
+    if RUNNING.with(|running| running.replace(true)) {
+        return;
+    }
+    let _guard = RunGuard;
+    let canisters: Vec<Principal> =
+        ALERTS.with(|alerts| alerts.borrow().alerts.keys().copied().collect());
+    for batch in canisters.chunks(CHECK_BATCH_SIZE) {
+        futures::future::join_all(batch.iter().map(|canister| async {
+            let result = check(&notifier, *canister).await;
+            ALERTS.with(|alerts| {
+                if let Some(alert) = alerts.borrow_mut().alerts.get_mut(canister) {
+                    alert.last_checked = Some(api::time());
+                    alert.last_error = result.err();
+                }
+            });
+        }))
+        .await;
+    }
+}
+
+async fn check(notifier: &Notifier, canister: Principal) -> Result<(), String> {
+    let balance = if canister == api::id() {
+        api::canister_balance128()
+    } else {
+        status::fetch(canister).await?.cycles
+    };
+    let threshold = match ALERTS.with(|alerts| {
+        alerts
+            .borrow_mut()
+            .alerts
+            .get_mut(&canister)
+            .and_then(|alert| {
+                alert.last_balance = Some(balance);
+                alert.should_alert(balance).then_some(alert.threshold.below)
+            })
+    }) {
+        Some(threshold) => threshold,
+        None => return Ok(()),
+    };
+    let alert = BalanceAlert {
+        wallet: api::id(),
+        canister,
+        balance,
+        threshold,
+        timestamp: api::time(),
+    };
+    api::call::notify(notifier.canister, &notifier.method, (alert,))
+        .map_err(|code| format!("Could not send the alert to the notifier: {}", code as u8))?;
+    ALERTS.with(|alerts| {
+        if let Some(alert) = alerts.borrow_mut().alerts.get_mut(&canister) {
+            alert.sent();
+        }
+    });
+    events::record_from(
+        api::id(),
+        TIMER_METHOD,
+        EventKind::BalanceAlertSent {
+            canister,
+            balance,
+            threshold,
+            notifier: notifier.canister,
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Alert, AlertThreshold};
+
+    #[test]
+    fn alerts_once_per_crossing() {
+        let mut alert = Alert {
+            threshold: AlertThreshold {
+                below: 100,
+                recover_above: None,
+            },
+            alerted: false,
+            last_balance: None,
+            last_checked: None,
+            last_error: None,
+        };
+        assert!(!alert.should_alert(100));
+        assert!(alert.should_alert(99));
+        // Not delivered yet, so it is tried again.
+        assert!(alert.should_alert(99));
+        alert.sent();
+        assert!(!alert.should_alert(50));
+        // Above the threshold, but not by enough to re-arm.
+        assert!(!alert.should_alert(105));
+        assert!(!alert.should_alert(99));
+        assert!(!alert.should_alert(110));
+        assert!(alert.should_alert(99));
+    }
+}
@@ -0,0 +1,292 @@
+use crate::chart::{self, Resolution};
+use crate::events::{
+    CyclesSource, EventKind, ManagedCanisterEventKind, EVENT_BUFFER, MANAGED_LIST,
+    MAX_SCANNED_EVENTS,
+};
+use crate::status::STATUSES;
+use candid::{CandidType, Principal};
+use ic_cdk::api;
+use serde::Deserialize;
+
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// The longest period rates are averaged over: as far back as the hourly chart goes.
+pub const MAX_PERIOD: u64 = 90 * DAY;
+/// The most managed canisters returned by a single call to [`canisters`].
+pub const MAX_CANISTERS: usize = 50;
+/// The most managed canisters looked at by a single call to [`canisters`].
+const MAX_SCANNED_CANISTERS: u64 = 1_000;
+
+/// How fast cycles leave the wallet, and how long its balance will last.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct BurnRate {
+    /// The period the rates are averaged over, in nanoseconds since the epoch. It is shorter than
+    /// requested if the chart doesn't go back that far, or more than 10,000 events were recorded over it.
+    pub from: u64,
+    pub to: u64,
+    pub balance: u128,
+    /// The cycles the wallet received, reclaimed from deleted canisters or withdrew from the cycles ledger.
+    pub received: u128,
+    /// The cycles the wallet sent, attached to calls or spent creating canisters.
+    pub spent: u128,
+    /// The cycles the wallet consumed itself, for compute and storage.
+    pub idle: u128,
+    pub burned_per_day: u128,
+    pub burned_per_week: u128,
+    /// How long the balance lasts at the current rate, in nanoseconds. `None` if nothing was burned.
+    pub runway: Option<u64>,
+}
+
+/// How fast the wallet funds a managed canister, and how long that canister's balance will last.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct CanisterBurnRate {
+    pub canister: Principal,
+    /// The cycles the wallet sent the canister, or had minted for it, over the period.
+    pub funded: u128,
+    pub funded_per_day: u128,
+    /// The balance and idle consumption from the last `refresh_managed_canister_statuses`.
+    pub balance: Option<u128>,
+    pub idle_burned_per_day: Option<u128>,
+    /// How long the balance lasts at the idle consumption, in nanoseconds.
+    pub runway: Option<u64>,
+}
+
+/// `amount` spread over `period` nanoseconds, per day.
+fn per_day(amount: u128, period: u64) -> u128 {
+    if period == 0 {
+        return 0;
+    }
+    amount.saturating_mul(DAY as u128) / period as u128
+}
+
+/// How long `balance` lasts when `burned_per_day` cycles are burned a day, in nanoseconds.
+fn runway(balance: u128, burned_per_day: u128) -> Option<u64> {
+    if burned_per_day == 0 {
+        return None;
+    }
+    let runway = balance.saturating_mul(DAY as u128) / burned_per_day;
+    Some(u64::try_from(runway).unwrap_or(u64::MAX))
+}
+
+/// Split what the balance lost between `from` and `to` into explicit spending and idle consumption.
+fn summarize(
+    (from, opening): (u64, u128),
+    (to, balance): (u64, u128),
+    received: u128,
+    spent: u128,
+) -> BurnRate {
+    let idle = opening
+        .saturating_add(received)
+        .saturating_sub(spent)
+        .saturating_sub(balance);
+    let burned_per_day = per_day(spent.saturating_add(idle), to.saturating_sub(from));
+    BurnRate {
+        from,
+        to,
+        balance,
+        received,
+        spent,
+        idle,
+        burned_per_day,
+        burned_per_week: burned_per_day.saturating_mul(7),
+        runway: runway(balance, burned_per_day),
+    }
+}
+
+/// The cycles an event moved out of the wallet's balance.
+fn outgoing(kind: &EventKind) -> u128 {
+    match kind {
+        EventKind::CyclesSent { amount, refund, .. }
+        | EventKind::CanisterToppedUp { amount, refund, .. }
+        | EventKind::CyclesLedgerDeposit { amount, refund, .. } => amount.saturating_sub(*refund),
+        EventKind::CanisterCalled { cycles, refund, .. } => {
+            cycles.saturating_sub(refund.unwrap_or_default())
+        }
+        EventKind::CanisterCreated { cycles, .. } => *cycles,
+        _ => 0,
+    }
+}
+
+/// The cycles an event added to the balance of the wallet `wallet`.
+fn incoming(kind: &EventKind, wallet: &Principal) -> u128 {
+    match kind {
+        // Cycles minted for a managed canister never reach the wallet.
+        EventKind::CyclesReceived {
+            source:
+                Some(CyclesSource::IcpConversion {
+                    canister: Some(_), ..
+                }),
+            ..
+        } => 0,
+        EventKind::CyclesReceived { amount, .. } => *amount,
+        EventKind::CanisterDeleted {
+            cycles_reclaimed, ..
+        } => *cycles_reclaimed,
+        EventKind::CyclesLedgerWithdrawal { to, amount, .. } if to == wallet => *amount,
+        _ => 0,
+    }
+}
+
+/// The cycles a managed canister event gave the canister.
+fn funding(kind: &ManagedCanisterEventKind) -> u128 {
+    match kind {
+        ManagedCanisterEventKind::CyclesSent { amount, refund }
+        | ManagedCanisterEventKind::ToppedUp { amount, refund, .. } => {
+            amount.saturating_sub(*refund)
+        }
+        ManagedCanisterEventKind::Called { cycles, refund, .. } => {
+            cycles.saturating_sub(refund.unwrap_or_default())
+        }
+        ManagedCanisterEventKind::Created { cycles }
+        | ManagedCanisterEventKind::ToppedUpWithIcp { cycles, .. } => *cycles,
+        _ => 0,
+    }
+}
+
+/// The wallet's burn rate over the last `period` nanoseconds, from its chart and event log.
+///
+/// At most [`MAX_SCANNED_EVENTS`] events are looked at; if more were recorded over the period, it
+/// is shortened to the latest of them.
+pub fn wallet(period: u64) -> BurnRate {
+    let now = api::time();
+    let balance = api::canister_balance128();
+    let wallet = api::id();
+    EVENT_BUFFER.with(|buffer| {
+        let buffer = buffer.borrow();
+        let total = buffer.total();
+        let mut start = now.saturating_sub(period.min(MAX_PERIOD));
+        let mut first = buffer.first_id_at(start);
+        if total.saturating_sub(first) > MAX_SCANNED_EVENTS {
+            first = total - MAX_SCANNED_EVENTS;
+            if let Some(event) = buffer.iter_between(first as usize..total as usize).next() {
+                start = event.timestamp;
+            }
+        }
+        // The balance at the start of the period, or as far back as the chart goes.
+        let opening = chart::get(Resolution::Hour, 0, start, 1)
+            .pop()
+            .map(|bucket| (start, bucket.close))
+            .or_else(|| {
+                chart::first(Resolution::Hour).map(|bucket| (bucket.start.max(start), bucket.close))
+            })
+            .unwrap_or((now, balance));
+
+        let first = first.max(buffer.first_id_at(opening.0)) as usize;
+        let (received, spent) = buffer.iter_between(first..total as usize).fold(
+            (0u128, 0u128),
+            |(received, spent), event| {
+                (
+                    received.saturating_add(incoming(&event.kind, &wallet)),
+                    spent.saturating_add(outgoing(&event.kind)),
+                )
+            },
+        );
+        summarize(opening, (now, balance), received, spent)
+    })
+}
+
+/// The burn rate of the managed canisters the wallet controls, over the last `period` nanoseconds.
+///
+/// Starting at the `from`th managed canister, at most `limit` canisters are returned, along with the
+/// index to continue from if the list wasn't exhausted. At most [`MAX_SCANNED_CANISTERS`] are looked at.
+pub fn canisters(period: u64, from: u32, limit: usize) -> (Vec<CanisterBurnRate>, Option<u32>) {
+    let now = api::time();
+    let period = period.min(MAX_PERIOD);
+    let start = now.saturating_sub(period);
+    MANAGED_LIST.with(|list| {
+        let list = list.borrow();
+        let end = list.len().min(from as u64 + MAX_SCANNED_CANISTERS);
+        let mut rates = Vec::new();
+        for index in from as u64..end {
+            if rates.len() == limit {
+                return (rates, Some(index as u32));
+            }
+            let canister = match list.get_by_index(index) {
+                Some(info) if list.controls(&info.id) => info.id,
+                _ => continue,
+            };
+            let funded = list
+                .events_since(&canister, start)
+                .iter()
+                .fold(0u128, |funded, event| {
+                    funded.saturating_add(funding(&event.kind))
+                });
+            let status = STATUSES.with(|statuses| {
+                statuses
+                    .borrow()
+                    .get(&canister)
+                    .and_then(|status| status.status.clone())
+            });
+            let balance = status.as_ref().map(|status| status.cycles);
+            let idle_burned_per_day = status.and_then(|status| status.idle_cycles_burned_per_day);
+            rates.push(CanisterBurnRate {
+                canister,
+                funded,
+                funded_per_day: per_day(funded, period),
+                balance,
+                idle_burned_per_day,
+                runway: balance
+                    .zip(idle_burned_per_day)
+                    .and_then(|(balance, burned)| runway(balance, burned)),
+            });
+        }
+        let next = (end < list.len()).then_some(end as u32);
+        (rates, next)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{incoming, outgoing, summarize, DAY};
+    use crate::events::EventKind;
+    use candid::Principal;
+
+    #[test]
+    fn separates_idle_consumption() {
+        // 1000 cycles a week ago, 300 received, 700 sent, and 100 left.
+        let rate = summarize((0, 1000), (7 * DAY, 100), 300, 700);
+        assert_eq!(rate.idle, 500);
+        assert_eq!(rate.burned_per_day, 1200 / 7);
+        assert_eq!(rate.burned_per_week, 1200 / 7 * 7);
+        assert_eq!(rate.runway, Some(100 * DAY / (1200 / 7)));
+
+        let rate = summarize((0, 1000), (DAY, 1000), 0, 0);
+        assert_eq!(rate.burned_per_day, 0);
+        assert_eq!(rate.runway, None);
+    }
+
+    #[test]
+    fn nets_out_refunds() {
+        let called = |refund| EventKind::CanisterCalled {
+            canister: Principal::anonymous(),
+            method_name: "greet".to_string(),
+            cycles: 1000,
+            refund,
+        };
+        // Attaching the whole balance to a call only spends what the callee kept.
+        assert_eq!(outgoing(&called(Some(990))), 10);
+        assert_eq!(outgoing(&called(None)), 1000);
+    }
+
+    #[test]
+    fn counts_reclaimed_and_withdrawn_cycles() {
+        let wallet = Principal::from_slice(&[1]);
+        let other = Principal::from_slice(&[2]);
+        let deleted = EventKind::CanisterDeleted {
+            canister: other,
+            cycles_reclaimed: 300,
+        };
+        let withdrawn = |to| EventKind::CyclesLedgerWithdrawal {
+            from_subaccount: None,
+            to,
+            amount: 200,
+            block_index: 0,
+        };
+        assert_eq!(incoming(&deleted, &wallet), 300);
+        assert_eq!(incoming(&withdrawn(wallet), &wallet), 200);
+        assert_eq!(incoming(&withdrawn(other), &wallet), 0);
+
+        // Without them, the reclaimed cycles would hide 500 cycles of idle consumption.
+        let rate = summarize((0, 1000), (DAY, 1000), 500, 0);
+        assert_eq!(rate.idle, 500);
+    }
+}
@@ -0,0 +1,240 @@
+use crate::memory::{self, Memory};
+use candid::CandidType;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::convert::TryInto;
+
+/// The most buckets returned by a single query.
+pub const MAX_BUCKETS: usize = 1000;
+
+/// A balance sample, as saved by wallets from before the chart was aggregated into buckets.
+#[derive(Clone, CandidType, Deserialize)]
+pub struct ChartTick {
+    pub timestamp: u64,
+    pub cycles: u64,
+}
+
+/// The length of time the balance is aggregated over.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum Resolution {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Resolution {
+    const ALL: [Resolution; 3] = [Resolution::Minute, Resolution::Hour, Resolution::Day];
+
+    /// The length of a bucket, in nanoseconds.
+    pub fn width(self) -> u64 {
+        match self {
+            Resolution::Minute => 60 * 1_000_000_000,
+            Resolution::Hour => 60 * 60 * 1_000_000_000,
+            Resolution::Day => 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+
+    /// The number of buckets kept: a day of minutes, 90 days of hours and five years of days.
+    fn retention(self) -> u64 {
+        match self {
+            Resolution::Minute => 24 * 60,
+            Resolution::Hour => 90 * 24,
+            Resolution::Day => 5 * 365,
+        }
+    }
+
+    /// The coarsest resolution whose buckets are no longer than `precision` nanoseconds.
+    pub fn for_precision(precision: u64) -> Self {
+        Self::ALL
+            .into_iter()
+            .rev()
+            .find(|resolution| resolution.width() <= precision)
+            .unwrap_or(Resolution::Minute)
+    }
+
+    fn memory_id(self) -> ic_stable_structures::memory_manager::MemoryId {
+        match self {
+            Resolution::Minute => memory::CHART_MINUTES,
+            Resolution::Hour => memory::CHART_HOURS,
+            Resolution::Day => memory::CHART_DAYS,
+        }
+    }
+}
+
+/// The balance of the wallet over a bucket of time.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct ChartBucket {
+    /// When the bucket starts, in nanoseconds since the epoch.
+    pub start: u64,
+    pub min: u128,
+    pub max: u128,
+    /// The last balance seen in the bucket.
+    pub close: u128,
+}
+
+impl Storable for ChartBucket {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.start.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.min.to_le_bytes());
+        bytes.extend_from_slice(&self.max.to_le_bytes());
+        bytes.extend_from_slice(&self.close.to_le_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let (start, rest) = bytes.split_at(8);
+        let (min, rest) = rest.split_at(16);
+        let (max, close) = rest.split_at(16);
+        Self {
+            start: u64::from_le_bytes(start.try_into().unwrap()),
+            min: u128::from_le_bytes(min.try_into().unwrap()),
+            max: u128::from_le_bytes(max.try_into().unwrap()),
+            close: u128::from_le_bytes(close.try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 56,
+        is_fixed_size: true,
+    };
+}
+
+/// The buckets of one resolution, by start time.
+type Buckets<M> = StableBTreeMap<u64, ChartBucket, M>;
+
+thread_local! {
+    static CHART: RefCell<Vec<(Resolution, Buckets<Memory>)>> = RefCell::new(
+        Resolution::ALL
+            .into_iter()
+            .map(|resolution| (resolution, Buckets::init(memory::get(resolution.memory_id()))))
+            .collect(),
+    );
+}
+
+/// Fold a balance of `cycles` at `timestamp` into its bucket, dropping the oldest buckets past retention.
+fn add<M: ic_stable_structures::Memory>(
+    buckets: &mut Buckets<M>,
+    resolution: Resolution,
+    timestamp: u64,
+    cycles: u128,
+) {
+    let start = timestamp - timestamp % resolution.width();
+    let bucket = match buckets.get(&start) {
+        Some(bucket) => ChartBucket {
+            start,
+            min: bucket.min.min(cycles),
+            max: bucket.max.max(cycles),
+            close: cycles,
+        },
+        None => ChartBucket {
+            start,
+            min: cycles,
+            max: cycles,
+            close: cycles,
+        },
+    };
+    buckets.insert(start, bucket);
+    while buckets.len() > resolution.retention() {
+        buckets.pop_first();
+    }
+}
+
+/// Record the wallet's balance at `timestamp` in every resolution.
+pub fn record(timestamp: u64, cycles: u128) {
+    CHART.with(|chart| {
+        for (resolution, buckets) in chart.borrow_mut().iter_mut() {
+            add(buckets, *resolution, timestamp, cycles);
+        }
+    });
+}
+
+/// The last `limit` buckets starting between `from` and `to`, oldest first.
+pub fn get(resolution: Resolution, from: u64, to: u64, limit: usize) -> Vec<ChartBucket> {
+    CHART.with(|chart| {
+        let chart = chart.borrow();
+        let Some((_, buckets)) = chart.iter().find(|(r, _)| *r == resolution) else {
+            return Vec::new();
+        };
+        let mut buckets: Vec<ChartBucket> = buckets
+            .range(from..=to)
+            .rev()
+            .take(limit)
+            .map(|(_, bucket)| bucket)
+            .collect();
+        buckets.reverse();
+        buckets
+    })
+}
+
+/// The earliest balance that can still show in the chart once one at `latest` was recorded, as
+/// only five years of days are kept.
+pub fn kept_since(latest: u64) -> u64 {
+    latest.saturating_sub(Resolution::Day.retention() * Resolution::Day.width())
+}
+
+/// The oldest bucket kept.
+pub fn first(resolution: Resolution) -> Option<ChartBucket> {
+    CHART.with(|chart| {
+        chart
+            .borrow()
+            .iter()
+            .find(|(r, _)| *r == resolution)
+            .and_then(|(_, buckets)| buckets.first_key_value())
+            .map(|(_, bucket)| bucket)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{add, Buckets, ChartBucket, Resolution};
+    use ic_stable_structures::VectorMemory;
+
+    const MINUTE: u64 = 60 * 1_000_000_000;
+
+    #[test]
+    fn aggregates_into_buckets() {
+        let mut buckets = Buckets::new(VectorMemory::default());
+        add(&mut buckets, Resolution::Minute, 0, 10);
+        add(&mut buckets, Resolution::Minute, 1, u128::MAX);
+        add(&mut buckets, Resolution::Minute, 2, 5);
+        add(&mut buckets, Resolution::Minute, MINUTE, 7);
+        assert_eq!(
+            buckets.iter().map(|(_, bucket)| bucket).collect::<Vec<_>>(),
+            vec![
+                ChartBucket {
+                    start: 0,
+                    min: 5,
+                    max: u128::MAX,
+                    close: 5
+                },
+                ChartBucket {
+                    start: MINUTE,
+                    min: 7,
+                    max: 7,
+                    close: 7
+                },
+            ]
+        );
+
+        // Only a day of minutes is kept.
+        for minute in 2..=24 * 60 {
+            add(&mut buckets, Resolution::Minute, minute * MINUTE, 1);
+        }
+        assert_eq!(buckets.len(), 24 * 60);
+        assert_eq!(buckets.first_key_value().unwrap().0, MINUTE);
+    }
+
+    #[test]
+    fn picks_resolution_for_precision() {
+        assert_eq!(Resolution::for_precision(1), Resolution::Minute);
+        assert_eq!(Resolution::for_precision(2 * MINUTE), Resolution::Minute);
+        assert_eq!(
+            Resolution::for_precision(Resolution::Hour.width()),
+            Resolution::Hour
+        );
+        assert_eq!(Resolution::for_precision(u64::MAX), Resolution::Day);
+    }
+}
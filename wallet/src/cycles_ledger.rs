@@ -0,0 +1,292 @@
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::cell::Cell;
+use std::convert::TryFrom;
+
+/// The cycles ledger on the IC mainnet. Local deployments can install it under the same ID.
+pub const MAINNET_CYCLES_LEDGER: &str = "um5iw-rqaaa-aaaaq-qaaba-cai";
+
+thread_local! {
+    /// The cycles ledger set with `set_cycles_ledger`, if it isn't the mainnet one.
+    pub static CYCLES_LEDGER: Cell<Option<Principal>> = Cell::new(None);
+}
+
+/// The cycles ledger this wallet talks to.
+pub fn id() -> Principal {
+    CYCLES_LEDGER
+        .with(|ledger| ledger.get())
+        .unwrap_or_else(|| {
+            Principal::from_text(MAINNET_CYCLES_LEDGER).expect("Invalid cycles ledger ID.")
+        })
+}
+
+/// An ICRC-1 account.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<ByteBuf>,
+}
+
+impl Account {
+    /// The default account of this wallet.
+    pub fn wallet() -> Self {
+        Self {
+            owner: api::id(),
+            subaccount: None,
+        }
+    }
+}
+
+/// The calls a custodian's call policy must allow to deposit into `to`, as `(canister, method)`.
+///
+/// Depositing into one of the wallet's own accounts needs none, since withdrawing the cycles again
+/// is checked. Depositing into anyone else's account counts as calling `deposit` on the cycles
+/// ledger and sending cycles to the owner.
+pub fn deposit_permissions(
+    to: &Account,
+    wallet: &Principal,
+    ledger: Principal,
+) -> Vec<(Principal, &'static str)> {
+    if &to.owner == wallet {
+        Vec::new()
+    } else {
+        vec![(ledger, "deposit"), (to.owner, "deposit_cycles")]
+    }
+}
+
+#[derive(CandidType)]
+struct DepositArgs {
+    to: Account,
+    memo: Option<ByteBuf>,
+}
+
+#[derive(Deserialize, CandidType)]
+struct DepositResult {
+    balance: Nat,
+    block_index: Nat,
+}
+
+#[derive(CandidType)]
+struct WithdrawArgs {
+    amount: Nat,
+    from_subaccount: Option<ByteBuf>,
+    to: Principal,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, CandidType)]
+enum RejectionCode {
+    NoError,
+    SysFatal,
+    SysTransient,
+    DestinationInvalid,
+    CanisterReject,
+    CanisterError,
+    Unknown,
+}
+
+#[derive(Debug, Deserialize, CandidType)]
+enum WithdrawError {
+    BadFee {
+        expected_fee: Nat,
+    },
+    InsufficientFunds {
+        balance: Nat,
+    },
+    TooOld,
+    CreatedInFuture {
+        ledger_time: u64,
+    },
+    TemporarilyUnavailable,
+    Duplicate {
+        duplicate_of: Nat,
+    },
+    FailedToWithdraw {
+        fee_block: Option<Nat>,
+        rejection_code: RejectionCode,
+        rejection_reason: String,
+    },
+    GenericError {
+        error_code: Nat,
+        message: String,
+    },
+    InvalidReceiver {
+        receiver: Principal,
+    },
+}
+
+#[derive(CandidType)]
+struct ApproveArgs {
+    from_subaccount: Option<ByteBuf>,
+    spender: Account,
+    amount: Nat,
+    expected_allowance: Option<Nat>,
+    expires_at: Option<u64>,
+    fee: Option<Nat>,
+    memo: Option<ByteBuf>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(Debug, Deserialize, CandidType)]
+enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// The outcome of a deposit that reached the cycles ledger.
+pub struct Deposit {
+    /// The cycles the ledger didn't take.
+    pub refund: u128,
+    /// The balance of the account after the deposit.
+    pub balance: u128,
+    pub block_index: u64,
+}
+
+fn nat_to_u128(nat: Nat) -> u128 {
+    u128::try_from(nat.0).unwrap_or(u128::MAX)
+}
+
+fn nat_to_u64(nat: Nat) -> u64 {
+    u64::try_from(nat.0).unwrap_or(u64::MAX)
+}
+
+fn call_error((code, msg): (api::call::RejectionCode, String)) -> String {
+    format!(
+        "An error happened during the call to the cycles ledger: {}: {}",
+        code as u8, msg
+    )
+}
+
+/// Deposit `amount` of this wallet's cycles into `to`. The ledger takes its fee out of the deposit.
+///
+/// On failure, returns the error along with the cycles that were refunded.
+pub async fn deposit(
+    to: Account,
+    memo: Option<ByteBuf>,
+    amount: u128,
+) -> Result<Deposit, (String, u128)> {
+    let result: Result<(DepositResult,), _> =
+        api::call::call_with_payment128(id(), "deposit", (DepositArgs { to, memo },), amount).await;
+    let refund = api::call::msg_cycles_refunded128();
+    match result {
+        Ok((DepositResult {
+            balance,
+            block_index,
+        },)) => Ok(Deposit {
+            refund,
+            balance: nat_to_u128(balance),
+            block_index: nat_to_u64(block_index),
+        }),
+        Err(err) => Err((call_error(err), refund)),
+    }
+}
+
+/// Withdraw `amount` cycles from one of this wallet's accounts into the canister `to`. Returns the block index.
+pub async fn withdraw(
+    from_subaccount: Option<ByteBuf>,
+    to: Principal,
+    amount: u128,
+) -> Result<u64, String> {
+    let (result,): (Result<Nat, WithdrawError>,) = api::call::call(
+        id(),
+        "withdraw",
+        (WithdrawArgs {
+            amount: Nat::from(amount),
+            from_subaccount,
+            to,
+            created_at_time: None,
+        },),
+    )
+    .await
+    .map_err(call_error)?;
+    result
+        .map(nat_to_u64)
+        .map_err(|err| format!("The cycles ledger rejected the withdrawal: {:?}", err))
+}
+
+/// Let `spender` transfer up to `amount` cycles from one of this wallet's accounts. Returns the block index.
+pub async fn approve(
+    from_subaccount: Option<ByteBuf>,
+    spender: Account,
+    amount: u128,
+    expires_at: Option<u64>,
+) -> Result<u64, String> {
+    let (result,): (Result<Nat, ApproveError>,) = api::call::call(
+        id(),
+        "icrc2_approve",
+        (ApproveArgs {
+            from_subaccount,
+            spender,
+            amount: Nat::from(amount),
+            expected_allowance: None,
+            expires_at,
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        },),
+    )
+    .await
+    .map_err(call_error)?;
+    result
+        .map(nat_to_u64)
+        .map_err(|err| format!("The cycles ledger rejected the approval: {:?}", err))
+}
+
+/// The cycles ledger balance of `account`.
+pub async fn balance_of(account: Account) -> Result<u128, String> {
+    let (balance,): (Nat,) = api::call::call(id(), "icrc1_balance_of", (account,))
+        .await
+        .map_err(call_error)?;
+    Ok(nat_to_u128(balance))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{deposit_permissions, Account};
+    use crate::address::{AddressBook, AddressEntry, CallPermission, CallPolicy, Role};
+    use candid::Principal;
+    use serde_bytes::ByteBuf;
+
+    #[test]
+    fn checks_deposits_into_other_accounts() {
+        let wallet = Principal::from_slice(&[1]);
+        let ledger = Principal::from_slice(&[2]);
+        let custodian = Principal::from_slice(&[3]);
+        let mut book: AddressBook = Default::default();
+        let mut entry = AddressEntry::new(custodian, None, Role::Custodian);
+        entry.call_policy = Some(CallPolicy {
+            allowed: vec![CallPermission {
+                canister: Some(ledger),
+                methods: vec!["*".to_string()],
+            }],
+        });
+        book.insert(entry);
+        let may_deposit = |to: &Account| {
+            deposit_permissions(to, &wallet, ledger)
+                .into_iter()
+                .all(|(canister, method)| book.may_call(&custodian, &canister, method))
+        };
+
+        assert!(may_deposit(&Account {
+            owner: wallet,
+            subaccount: Some(ByteBuf::from(vec![1; 32])),
+        }));
+        assert!(!may_deposit(&Account {
+            owner: custodian,
+            subaccount: None,
+        }));
+        assert!(may_deposit(&Account {
+            owner: ledger,
+            subaccount: None,
+        }));
+    }
+}
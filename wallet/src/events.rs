@@ -1,27 +1,57 @@
 use crate::address::Role;
-use candid::types::{Compound, Serializer, Type, TypeInner};
+use crate::cycles_ledger::Account;
+use crate::memory::{self, Memory};
 use candid::CandidType;
 use candid::Principal;
 use ic_cdk::api;
-use indexmap::IndexMap;
-use serde::de::{SeqAccess, Visitor};
-use serde::{Deserialize, Deserializer};
+use ic_certified_map::Hash;
+use ic_stable_structures::{StableBTreeMap, StableVec};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
 use std::cell::RefCell;
 use std::cmp::min;
-use std::collections::VecDeque;
-use std::fmt::{self, Formatter};
 use std::ops::Range;
 
-#[derive(CandidType, Clone, Default, Deserialize)]
+/// The event log of this wallet, kept in stable memory and indexed by event ID.
+///
+/// Events are stored candid-encoded; each event holds the hash of the bytes of the one before it.
 pub struct EventBuffer {
-    pub events: VecDeque<Event>,
+    events: StableBTreeMap<u32, Vec<u8>, Memory>,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Default)]
-pub struct ManagedList(pub IndexMap<Principal, ManagedCanister>);
+impl Default for EventBuffer {
+    fn default() -> Self {
+        Self {
+            events: StableBTreeMap::init(memory::get(memory::EVENTS)),
+        }
+    }
+}
 
-const MAX_EVENTS: usize = 10_000;
-const MAX_CANISTER_EVENTS: usize = 1_000;
+/// The canisters this wallet interacted with and their events, kept in stable memory.
+pub struct ManagedList {
+    /// The managed canisters, in the order they were first seen.
+    ids: StableVec<Principal, Memory>,
+    info: StableBTreeMap<Principal, ManagedCanisterInfo, Memory>,
+    events: StableBTreeMap<(Principal, u32), ManagedCanisterEvent, Memory>,
+}
+
+impl Default for ManagedList {
+    fn default() -> Self {
+        Self {
+            ids: StableVec::init(memory::get(memory::MANAGED_CANISTER_IDS))
+                .expect("Could not initialize the managed canister list."),
+            info: StableBTreeMap::init(memory::get(memory::MANAGED_CANISTER_INFO)),
+            events: StableBTreeMap::init(memory::get(memory::MANAGED_CANISTER_EVENTS)),
+        }
+    }
+}
+
+/// The most events returned by a single call to [`get_events_page`].
+const MAX_PAGE_SIZE: usize = 1_000;
+/// The most events examined by a single query, so scans stay within the instruction limit.
+pub const MAX_SCANNED_EVENTS: u32 = 10_000;
+const MAX_CANISTER_EVENTS: u32 = 1_000;
 
 thread_local! {
     pub static EVENT_BUFFER: RefCell<EventBuffer> = Default::default();
@@ -38,26 +68,137 @@ impl ManagedList {
         event: ManagedCanisterEventKind,
         timestamp: u64,
     ) {
-        let canister = &mut self
-            .0
-            .entry(canister)
-            .or_insert_with(|| ManagedCanister::new(canister));
-        let events = &mut canister.events;
-        events.push_back(ManagedCanisterEvent {
-            kind: event,
-            id: events.len() as u32,
-            timestamp,
-        });
-        if events.len() > MAX_CANISTER_EVENTS {
-            events.drain(..events.len() - MAX_CANISTER_EVENTS);
+        if !self.info.contains_key(&canister) {
+            self.insert_info(ManagedCanisterInfo {
+                id: canister,
+                name: None,
+                created_at: api::time(),
+                deleted_at: None,
+                controlled: None,
+            });
         }
+        match event {
+            ManagedCanisterEventKind::Deleted { .. } => {
+                if let Some(mut info) = self.info.get(&canister) {
+                    info.deleted_at = Some(timestamp);
+                    self.insert_info(info);
+                }
+            }
+            // Only a controller can create a canister or install code on it.
+            ManagedCanisterEventKind::Created { .. }
+            | ManagedCanisterEventKind::CodeInstalled { .. } => {
+                self.set_controlled(&canister, true)
+            }
+            _ => (),
+        }
+        let id = self.last_event_id(&canister).map_or(0, |id| id + 1);
+        self.insert_event(
+            canister,
+            ManagedCanisterEvent {
+                kind: event,
+                id,
+                timestamp,
+            },
+        );
     }
-}
 
-#[derive(Debug, Clone, Eq, CandidType, Deserialize)]
-pub struct ManagedCanister {
-    pub info: ManagedCanisterInfo,
-    pub events: VecDeque<ManagedCanisterEvent>,
+    /// Add a canister to the end of the list, or update its info if it is already known.
+    pub fn insert_info(&mut self, info: ManagedCanisterInfo) {
+        if self.info.insert(info.id, info.clone()).is_none() {
+            self.ids
+                .push(&info.id)
+                .expect("Could not grow the managed canister list.");
+        }
+    }
+
+    /// Whether the wallet was seen to control a canister it didn't delete.
+    pub fn controls(&self, canister: &Principal) -> bool {
+        self.get(canister).map_or(false, |info| {
+            info.deleted_at.is_none() && info.controlled == Some(true)
+        })
+    }
+
+    /// Record whether the wallet controls a canister it knows about.
+    pub fn set_controlled(&mut self, canister: &Principal, controlled: bool) {
+        if let Some(mut info) = self.info.get(canister) {
+            if info.controlled != Some(controlled) {
+                info.controlled = Some(controlled);
+                self.insert_info(info);
+            }
+        }
+    }
+
+    /// Whether the wallet may control a canister: it wasn't deleted, and wasn't seen to be out of
+    /// the wallet's control.
+    pub fn may_control(&self, canister: &Principal) -> bool {
+        self.get(canister).map_or(false, |info| {
+            info.deleted_at.is_none() && info.controlled != Some(false)
+        })
+    }
+
+    /// Add an event to a canister's history, dropping its oldest events past the limit.
+    pub fn insert_event(&mut self, canister: Principal, event: ManagedCanisterEvent) {
+        let id = event.id;
+        self.events.insert((canister, id), event);
+        while let Some((key, _)) = self.events.range((canister, 0)..).next() {
+            if key.0 != canister || id.saturating_sub(key.1) < MAX_CANISTER_EVENTS {
+                break;
+            }
+            self.events.remove(&key);
+        }
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.ids.len()
+    }
+
+    #[inline]
+    pub fn get(&self, canister: &Principal) -> Option<ManagedCanisterInfo> {
+        self.info.get(canister)
+    }
+
+    /// The IDs of the managed canisters, in the order they were first seen.
+    #[inline]
+    pub fn ids(&self) -> impl Iterator<Item = Principal> + '_ {
+        self.ids.iter()
+    }
+
+    /// The info of the `index`th managed canister.
+    #[inline]
+    pub fn get_by_index(&self, index: u64) -> Option<ManagedCanisterInfo> {
+        self.get(&self.ids.get(index)?)
+    }
+
+    fn last_event_id(&self, canister: &Principal) -> Option<u32> {
+        self.events
+            .keys_range((*canister, 0)..=(*canister, u32::MAX))
+            .next_back()
+            .map(|(_, id)| id)
+    }
+
+    /// The events of a canister with IDs in `range`.
+    pub fn events_between(
+        &self,
+        canister: &Principal,
+        Range { start, end }: Range<u32>,
+    ) -> Vec<ManagedCanisterEvent> {
+        self.events
+            .values_range((*canister, start.min(end))..(*canister, end))
+            .collect()
+    }
+
+    /// The events of a canister recorded at or after `timestamp`.
+    pub fn events_since(&self, canister: &Principal, timestamp: u64) -> Vec<ManagedCanisterEvent> {
+        let mut events: Vec<ManagedCanisterEvent> = self
+            .events
+            .values_range((*canister, 0)..=(*canister, u32::MAX))
+            .rev()
+            .take_while(|event| event.timestamp >= timestamp)
+            .collect();
+        events.reverse();
+        events
+    }
 }
 
 #[derive(Debug, Clone, Eq, CandidType, Deserialize)]
@@ -65,19 +206,11 @@ pub struct ManagedCanisterInfo {
     pub id: Principal,
     pub name: Option<String>,
     pub created_at: u64,
-}
-
-impl ManagedCanister {
-    pub fn new(id: Principal) -> Self {
-        Self {
-            info: ManagedCanisterInfo {
-                id,
-                name: None,
-                created_at: api::time(),
-            },
-            events: VecDeque::new(),
-        }
-    }
+    /// When the wallet deleted the canister. Its events are kept.
+    pub deleted_at: Option<u64>,
+    /// Whether the wallet controls the canister, as last seen: it created the canister or installed
+    /// code on it, or the canister's status was fetched. `None` if it is unknown.
+    pub controlled: Option<bool>,
 }
 
 impl PartialEq for ManagedCanisterInfo {
@@ -86,12 +219,6 @@ impl PartialEq for ManagedCanisterInfo {
     }
 }
 
-impl PartialEq for ManagedCanister {
-    fn eq(&self, other: &Self) -> bool {
-        self.info == other.info
-    }
-}
-
 #[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
 pub struct ManagedCanisterEvent {
     pub id: u32,
@@ -99,34 +226,127 @@ pub struct ManagedCanisterEvent {
     pub kind: ManagedCanisterEventKind,
 }
 
+/// How code is installed on a canister.
+#[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
+pub enum InstallMode {
+    #[serde(rename = "install")]
+    Install,
+    #[serde(rename = "reinstall")]
+    Reinstall,
+    #[serde(rename = "upgrade")]
+    Upgrade,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, CandidType, Deserialize)]
 pub enum ManagedCanisterEventKind {
-    CyclesSent { amount: u128, refund: u128 },
-    Called { method_name: String, cycles: u128 },
-    Created { cycles: u128 },
+    CyclesSent {
+        amount: u128,
+        refund: u128,
+    },
+    Called {
+        method_name: String,
+        cycles: u128,
+        /// The cycles the canister didn't accept. `None` for calls recorded before refunds were tracked.
+        refund: Option<u128>,
+    },
+    Created {
+        cycles: u128,
+    },
+    CodeInstalled {
+        mode: InstallMode,
+        module_hash: ByteBuf,
+    },
+    Stopped,
+    Started,
+    /// The wallet deleted the canister, after withdrawing `cycles_reclaimed` cycles from it.
+    Deleted {
+        cycles_reclaimed: u128,
+    },
+    /// The cycles minting canister minted `cycles` for the canister, from ICP the wallet sent it.
+    ToppedUpWithIcp {
+        icp_e8s: u64,
+        cycles: u128,
+    },
+    /// The wallet topped the canister up because its balance was below its top-up policy's minimum.
+    ToppedUp {
+        amount: u128,
+        refund: u128,
+        balance: u128,
+    },
 }
 
 impl EventBuffer {
+    /// Append an event to the log, chaining it to the current tip.
     #[inline]
-    pub fn push(&mut self, event: Event) {
-        self.events.push_back(event);
+    pub fn push(&mut self, mut event: Event) {
+        event.prev_hash = self.tip().map(|hash| ByteBuf::from(hash.to_vec()));
+        self.events
+            .insert(event.id, memory::encode(&event).into_owned());
+    }
+
+    /// The hash of the last event, which transitively covers every event since the log was chained.
+    pub fn tip(&self) -> Option<Hash> {
+        self.events
+            .last_key_value()
+            .map(|(_, bytes)| Sha256::digest(bytes).into())
     }
 
     #[inline]
     pub fn total(&self) -> u32 {
-        self.events.back().map(|event| event.id).unwrap_or(0) + 1
+        self.events.keys().next_back().unwrap_or(0) + 1
+    }
+
+    /// The ID of the first event recorded at or after `timestamp`, or [`total`](Self::total) if there is none.
+    ///
+    /// Event timestamps never decrease, so this is a binary search.
+    pub fn first_id_at(&self, timestamp: u64) -> u32 {
+        let mut low = self.events.keys().next().unwrap_or(0);
+        let mut high = self.total();
+        while low < high {
+            let mid = low + (high - low) / 2;
+            match self
+                .events
+                .get(&mid)
+                .map(|bytes| memory::decode::<Event>(&bytes))
+            {
+                Some(event) if event.timestamp < timestamp => low = mid + 1,
+                _ => high = mid,
+            }
+        }
+        low
     }
 
     #[inline]
-    pub fn between(&self, Range { start, end }: Range<usize>) -> Vec<Event> {
-        let base = self.events.front().map(|event| event.id).unwrap_or(0) as usize;
-        self.events
-            .range(start.saturating_sub(base)..end.saturating_sub(base))
-            .cloned()
-            .collect()
+    pub fn iter_between(
+        &self,
+        Range { start, end }: Range<usize>,
+    ) -> impl DoubleEndedIterator<Item = Event> + '_ {
+        self.raw_between(start..end)
+            .map(|bytes| memory::decode(&bytes))
+    }
+
+    /// The candid-encoded events with IDs in the range, exactly as they were hashed.
+    #[inline]
+    pub fn raw_between(
+        &self,
+        Range { start, end }: Range<usize>,
+    ) -> impl DoubleEndedIterator<Item = Vec<u8>> + '_ {
+        self.events.values_range(start.min(end) as u32..end as u32)
     }
 }
 
+/// Where received cycles came from.
+#[derive(CandidType, Clone, Debug, PartialEq, Eq, Deserialize)]
+pub enum CyclesSource {
+    /// The cycles minting canister minted the cycles from ICP the wallet sent it in block `block_index`.
+    IcpConversion {
+        icp_e8s: u64,
+        block_index: u64,
+        /// The managed canister the cycles were minted for. `None` if they went to the wallet.
+        canister: Option<Principal>,
+    },
+}
+
 /// The type of an event in the event logs.
 #[derive(CandidType, Clone, Deserialize)]
 pub enum EventKind {
@@ -134,11 +354,16 @@ pub enum EventKind {
         to: Principal,
         amount: u128,
         refund: u128,
+        /// The scheduled payment this was made for, if any.
+        schedule: Option<u64>,
+        memo: Option<String>,
     },
     CyclesReceived {
         from: Principal,
         amount: u128,
         memo: Option<String>,
+        /// How the cycles were obtained, when the wallet knows. `None` for cycles sent to `wallet_receive`.
+        source: Option<CyclesSource>,
     },
     AddressAdded {
         id: Principal,
@@ -156,13 +381,120 @@ pub enum EventKind {
         canister: Principal,
         method_name: String,
         cycles: u128,
+        /// The cycles the canister didn't accept. `None` for calls recorded before refunds were tracked.
+        refund: Option<u128>,
     },
     WalletDeployed {
         canister: Principal,
     },
+    ProposalSubmitted {
+        id: u64,
+        proposer: Principal,
+    },
+    ProposalApproved {
+        id: u64,
+        controller: Principal,
+    },
+    ProposalRejected {
+        id: u64,
+        controller: Principal,
+    },
+    ProposalExecuted {
+        id: u64,
+        error: Option<String>,
+    },
+    ProposalExpired {
+        id: u64,
+    },
+    CodeInstalled {
+        canister: Principal,
+        mode: InstallMode,
+        /// The SHA-256 of the installed module.
+        module_hash: ByteBuf,
+    },
+    CanisterStopped {
+        canister: Principal,
+    },
+    CanisterStarted {
+        canister: Principal,
+    },
+    CanisterDeleted {
+        canister: Principal,
+        cycles_reclaimed: u128,
+    },
+    CanisterToppedUp {
+        canister: Principal,
+        amount: u128,
+        refund: u128,
+        /// The canister's balance before the top-up.
+        balance: u128,
+    },
+    /// A custodian's call policy stopped them from calling `method_name` on `canister`.
+    CallDenied {
+        canister: Principal,
+        method_name: String,
+    },
+    /// The wallet deposited some of its cycles into a cycles ledger account.
+    CyclesLedgerDeposit {
+        to: Account,
+        amount: u128,
+        refund: u128,
+        block_index: u64,
+    },
+    /// The wallet withdrew cycles from one of its cycles ledger accounts into a canister.
+    CyclesLedgerWithdrawal {
+        from_subaccount: Option<ByteBuf>,
+        to: Principal,
+        amount: u128,
+        block_index: u64,
+    },
+    /// The wallet let `spender` transfer cycles from one of its cycles ledger accounts.
+    CyclesLedgerApproval {
+        from_subaccount: Option<ByteBuf>,
+        spender: Account,
+        amount: u128,
+        expires_at: Option<u64>,
+        block_index: u64,
+    },
+    /// The balance of the wallet, or of one of its managed canisters, fell below its alert threshold,
+    /// and the notifier was told.
+    BalanceAlertSent {
+        canister: Principal,
+        balance: u128,
+        threshold: u128,
+        notifier: Principal,
+    },
 }
 
 impl EventKind {
+    /// The name of this kind of event, as used by event filters.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CyclesSent { .. } => "CyclesSent",
+            Self::CyclesReceived { .. } => "CyclesReceived",
+            Self::AddressAdded { .. } => "AddressAdded",
+            Self::AddressRemoved { .. } => "AddressRemoved",
+            Self::CanisterCreated { .. } => "CanisterCreated",
+            Self::CanisterCalled { .. } => "CanisterCalled",
+            Self::WalletDeployed { .. } => "WalletDeployed",
+            Self::ProposalSubmitted { .. } => "ProposalSubmitted",
+            Self::ProposalApproved { .. } => "ProposalApproved",
+            Self::ProposalRejected { .. } => "ProposalRejected",
+            Self::ProposalExecuted { .. } => "ProposalExecuted",
+            Self::ProposalExpired { .. } => "ProposalExpired",
+            Self::CodeInstalled { .. } => "CodeInstalled",
+            Self::CanisterStopped { .. } => "CanisterStopped",
+            Self::CanisterStarted { .. } => "CanisterStarted",
+            Self::CanisterDeleted { .. } => "CanisterDeleted",
+            Self::CanisterToppedUp { .. } => "CanisterToppedUp",
+            Self::CallDenied { .. } => "CallDenied",
+            Self::CyclesLedgerDeposit { .. } => "CyclesLedgerDeposit",
+            Self::CyclesLedgerWithdrawal { .. } => "CyclesLedgerWithdrawal",
+            Self::CyclesLedgerApproval { .. } => "CyclesLedgerApproval",
+            Self::BalanceAlertSent { .. } => "BalanceAlertSent",
+        }
+    }
+
     pub fn to_managed(&self) -> Option<(Principal, ManagedCanisterEventKind)> {
         match *self {
             Self::CanisterCreated { cycles, canister } => {
@@ -172,20 +504,85 @@ impl EventKind {
                 canister,
                 ref method_name,
                 cycles,
+                refund,
             } => Some((
                 canister,
                 ManagedCanisterEventKind::Called {
                     method_name: method_name.clone(),
                     cycles,
+                    refund,
+                },
+            )),
+            Self::CyclesSent {
+                to, amount, refund, ..
+            } => Some((to, ManagedCanisterEventKind::CyclesSent { amount, refund })),
+            Self::CyclesReceived {
+                amount,
+                source:
+                    Some(CyclesSource::IcpConversion {
+                        icp_e8s,
+                        canister: Some(canister),
+                        ..
+                    }),
+                ..
+            } => Some((
+                canister,
+                ManagedCanisterEventKind::ToppedUpWithIcp {
+                    icp_e8s,
+                    cycles: amount,
                 },
             )),
-            Self::CyclesSent { to, amount, refund } => {
-                Some((to, ManagedCanisterEventKind::CyclesSent { amount, refund }))
+            Self::CodeInstalled {
+                canister,
+                ref mode,
+                ref module_hash,
+            } => Some((
+                canister,
+                ManagedCanisterEventKind::CodeInstalled {
+                    mode: mode.clone(),
+                    module_hash: module_hash.clone(),
+                },
+            )),
+            Self::CanisterStopped { canister } => {
+                Some((canister, ManagedCanisterEventKind::Stopped))
+            }
+            Self::CanisterStarted { canister } => {
+                Some((canister, ManagedCanisterEventKind::Started))
             }
+            Self::CanisterDeleted {
+                canister,
+                cycles_reclaimed,
+            } => Some((
+                canister,
+                ManagedCanisterEventKind::Deleted { cycles_reclaimed },
+            )),
+            Self::CanisterToppedUp {
+                canister,
+                amount,
+                refund,
+                balance,
+            } => Some((
+                canister,
+                ManagedCanisterEventKind::ToppedUp {
+                    amount,
+                    refund,
+                    balance,
+                },
+            )),
             Self::AddressAdded { .. }
             | Self::AddressRemoved { .. }
             | Self::CyclesReceived { .. }
-            | Self::WalletDeployed { .. } => None,
+            | Self::WalletDeployed { .. }
+            | Self::ProposalSubmitted { .. }
+            | Self::ProposalApproved { .. }
+            | Self::ProposalRejected { .. }
+            | Self::ProposalExecuted { .. }
+            | Self::ProposalExpired { .. }
+            | Self::CallDenied { .. }
+            | Self::CyclesLedgerDeposit { .. }
+            | Self::CyclesLedgerWithdrawal { .. }
+            | Self::CyclesLedgerApproval { .. }
+            | Self::BalanceAlertSent { .. } => None,
         }
     }
 }
@@ -195,14 +592,27 @@ pub struct Event {
     pub id: u32,
     pub timestamp: u64,
     pub kind: EventKind,
+    /// The principal whose call caused this event. `None` for events recorded before callers were tracked.
+    pub caller: Option<Principal>,
+    /// The wallet API method that caused this event. `None` for events recorded before methods were tracked.
+    pub method: Option<String>,
+    /// The SHA-256 hash of the candid encoding of the previous event, as returned by `get_certified_events`.
+    /// `None` for the first event and events recorded before the log was chained.
+    pub prev_hash: Option<ByteBuf>,
 }
 
-/// Record an event.
-pub fn record(kind: EventKind) {
+/// Record an event caused by the current caller, through the wallet API method `method`.
+pub fn record(method: &str, kind: EventKind) {
+    record_from(api::caller(), method, kind)
+}
+
+/// Record an event caused by `caller`, through the wallet API method `method`.
+pub fn record_from(caller: Principal, method: &str, kind: EventKind) {
     if let Some((to, kind)) = kind.to_managed() {
         MANAGED_LIST.with(|managed| managed.borrow_mut().push(to, kind));
     }
-    EVENT_BUFFER.with(|buffer| {
+    let name = kind.name();
+    let id = EVENT_BUFFER.with(|buffer| {
         let mut buffer = buffer.borrow_mut();
         let buffer = &mut *buffer;
         let len = buffer.total();
@@ -210,27 +620,143 @@ pub fn record(kind: EventKind) {
             id: len,
             timestamp: api::time(),
             kind,
+            caller: Some(caller),
+            method: Some(method.to_string()),
+            prev_hash: None,
         });
-        if buffer.events.len() > MAX_EVENTS {
-            buffer.events.drain(..buffer.events.len() - MAX_EVENTS);
-        }
+        len
     });
+    crate::subscriptions::enqueue(id, name);
+    crate::update_certified_data();
 }
 
-pub fn get_events(from: Option<u32>, to: Option<u32>) -> Vec<Event> {
+/// The hash of the last event, or `None` if no event was recorded.
+pub fn tip() -> Option<Hash> {
+    EVENT_BUFFER.with(|buffer| buffer.borrow().tip())
+}
+
+/// Get the candid-encoded events with IDs between `from` and `to`, oldest first, so their hashes can be checked.
+///
+/// At most `limit` events (default 20, maximum 1,000) are returned. If `from` is unspecified, returns the last
+/// `limit` events before `to`.
+pub fn get_raw_events(from: Option<u32>, to: Option<u32>, limit: Option<u32>) -> Vec<ByteBuf> {
     EVENT_BUFFER.with(|buffer| {
         let buffer = buffer.borrow();
-
-        let from = from.unwrap_or_else(|| {
-            if buffer.total() <= 20 {
-                0
-            } else {
-                buffer.total() - 20
-            }
-        }) as usize;
         let to = min(buffer.total(), to.unwrap_or(u32::MAX)) as usize;
+        let limit = limit.map_or(20, |limit| limit as usize).min(MAX_PAGE_SIZE);
+        let events = match from {
+            Some(from) => buffer.raw_between(from as usize..to).take(limit).collect(),
+            None => {
+                let mut events: Vec<_> = buffer.raw_between(0..to).rev().take(limit).collect();
+                events.reverse();
+                events
+            }
+        };
+        events.into_iter().map(ByteBuf::from).collect()
+    })
+}
+
+/// Restricts which events [`get_events_page`] returns.
+#[derive(Default)]
+pub struct EventFilter {
+    /// Only events recorded at or after this time.
+    pub from_timestamp: Option<u64>,
+    /// Only events recorded before this time.
+    pub to_timestamp: Option<u64>,
+    /// Only events whose kind has one of these names, e.g. `"CyclesSent"`.
+    pub kinds: Option<Vec<String>>,
+    /// Only events caused by this principal.
+    pub caller: Option<Principal>,
+}
+
+impl EventFilter {
+    fn matches(&self, event: &Event) -> bool {
+        self.kinds.as_ref().map_or(true, |kinds| {
+            kinds.iter().any(|kind| kind == event.kind.name())
+        }) && self
+            .caller
+            .as_ref()
+            .map_or(true, |caller| event.caller.as_ref() == Some(caller))
+    }
+}
 
-        buffer.between(from..to)
+/// A page of the event log.
+#[derive(CandidType, Deserialize)]
+pub struct EventPage {
+    pub events: Vec<Event>,
+    /// The `from` to pass to get the next page, or `None` if there are no more events to look at.
+    pub next: Option<u32>,
+    /// When paging back from the latest events, the `to` to pass to get the page before, or `None`
+    /// if there are no earlier events to look at.
+    pub prev: Option<u32>,
+}
+
+/// Get the events with IDs between `from` and `to` that match `filter`, oldest first.
+///
+/// At most `limit` events (default and maximum 1,000, minimum 1) are returned, and `next` points past the last
+/// event looked at. If `from` is unspecified, returns the last `limit` (default 20) matching events before `to`,
+/// and `prev` points at the first event looked at. At most 10,000 events are looked at either way.
+pub fn get_events_page(
+    from: Option<u32>,
+    to: Option<u32>,
+    limit: Option<u32>,
+    filter: &EventFilter,
+) -> EventPage {
+    EVENT_BUFFER.with(|buffer| {
+        let buffer = buffer.borrow();
+        let mut to = min(buffer.total(), to.unwrap_or(u32::MAX));
+        if let Some(to_timestamp) = filter.to_timestamp {
+            to = min(to, buffer.first_id_at(to_timestamp));
+        }
+        let start = filter
+            .from_timestamp
+            .map_or(0, |from_timestamp| buffer.first_id_at(from_timestamp));
+
+        match from {
+            Some(from) => {
+                let limit = limit.map_or(MAX_PAGE_SIZE, |limit| limit as usize);
+                let limit = limit.clamp(1, MAX_PAGE_SIZE);
+                let mut events = vec![];
+                let mut next = None;
+                let range = from.max(start) as usize..to as usize;
+                for (scanned, event) in buffer.iter_between(range).enumerate() {
+                    if events.len() == limit || scanned == MAX_SCANNED_EVENTS as usize {
+                        next = Some(event.id);
+                        break;
+                    }
+                    if filter.matches(&event) {
+                        events.push(event);
+                    }
+                }
+                EventPage {
+                    events,
+                    next,
+                    prev: None,
+                }
+            }
+            None => {
+                let limit = limit.map_or(20, |limit| limit as usize);
+                let limit = limit.clamp(1, MAX_PAGE_SIZE);
+                let mut events = vec![];
+                let mut prev = None;
+                let range = start as usize..to as usize;
+                for (scanned, event) in buffer.iter_between(range).rev().enumerate() {
+                    if events.len() == limit || scanned == MAX_SCANNED_EVENTS as usize {
+                        prev = Some(event.id + 1);
+                        break;
+                    }
+                    if filter.matches(&event) {
+                        events.push(event);
+                    }
+                }
+                events.reverse();
+                EventPage {
+                    events,
+                    next: None,
+                    prev,
+                }
+            }
+        }
     })
 }
 
@@ -241,11 +767,11 @@ pub fn get_managed_canisters(
 ) -> (Vec<ManagedCanisterInfo>, u32) {
     MANAGED_LIST.with(|list| {
         let list = list.borrow();
-        let from = from.unwrap_or(0) as usize;
-        let to = min(list.0.len(), to.unwrap_or(u32::MAX) as usize);
+        let from = from.unwrap_or(0) as u64;
+        let to = min(list.len(), to.unwrap_or(u32::MAX) as u64);
         (
-            (from..to).map(|n| list.0[n].info.clone()).collect(),
-            list.0.len() as u32,
+            (from..to).filter_map(|n| list.get_by_index(n)).collect(),
+            list.len() as u32,
         )
     })
 }
@@ -258,20 +784,13 @@ pub fn get_managed_canister_events(
     from: Option<u32>,
     to: Option<u32>,
 ) -> Option<Vec<ManagedCanisterEvent>> {
-    MANAGED_LIST.with(|buffer| {
-        let buffer = buffer.borrow();
-        let canister = &buffer.0.get(canister)?;
-        let buffer = &canister.events;
-        let total = buffer.back().map(|event| event.id).unwrap_or(0) + 1;
-        let from = from.unwrap_or(if total <= 20 { 0 } else { total - 20 }) as usize;
-        let to = min(total, to.unwrap_or(u32::MAX)) as usize;
-        let base = buffer.front().map(|event| event.id).unwrap_or(0) as usize;
-        Some(
-            buffer
-                .range(from.saturating_sub(base)..to.saturating_sub(base))
-                .cloned()
-                .collect(),
-        )
+    MANAGED_LIST.with(|list| {
+        let list = list.borrow();
+        list.get(canister)?;
+        let total = list.last_event_id(canister).unwrap_or(0) + 1;
+        let from = from.unwrap_or(if total <= 20 { 0 } else { total - 20 });
+        let to = min(total, to.unwrap_or(u32::MAX));
+        Some(list.events_between(canister, from..to))
     })
 }
 
@@ -279,52 +798,140 @@ pub fn get_managed_canister_events(
 pub fn set_short_name(canister: &Principal, name: Option<String>) -> Option<ManagedCanisterInfo> {
     MANAGED_LIST.with(|list| {
         let mut list = list.borrow_mut();
-        let canister = list.0.get_mut(canister)?;
-        canister.info.name = name;
-        Some(canister.info.clone())
+        let mut info = list.get(canister)?;
+        info.name = name;
+        list.insert_info(info.clone());
+        Some(info)
     })
 }
 
-impl CandidType for ManagedList {
-    fn _ty() -> Type {
-        Type(<_>::from(TypeInner::Vec(ManagedCanister::ty())))
-    }
-    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
-    where
-        S: Serializer,
-    {
-        let mut compound = serializer.serialize_vec(self.0.len())?;
-        for value in self.0.values() {
-            compound.serialize_element(value)?;
-        }
-        Ok(())
-    }
-}
+#[cfg(test)]
+mod tests {
+    use super::{
+        get_events_page, get_raw_events, tip, Event, EventFilter, EventKind, ManagedCanisterInfo,
+        ManagedList, EVENT_BUFFER,
+    };
+    use candid::Principal;
+    use sha2::{Digest, Sha256};
 
-impl<'de> Deserialize<'de> for ManagedList {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        deserializer.deserialize_seq(IdxMapVisitor)
-    }
-}
+    #[test]
+    fn pages_through_filtered_events() {
+        EVENT_BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            for id in 1..=50 {
+                let kind = if id % 2 == 0 {
+                    EventKind::WalletDeployed {
+                        canister: Principal::anonymous(),
+                    }
+                } else {
+                    EventKind::AddressRemoved {
+                        id: Principal::anonymous(),
+                    }
+                };
+                buffer.push(Event {
+                    id,
+                    timestamp: id as u64 * 10,
+                    kind,
+                    caller: if id > 48 {
+                        Some(Principal::anonymous())
+                    } else {
+                        None
+                    },
+                    method: None,
+                    prev_hash: None,
+                });
+            }
+        });
+        let filter = EventFilter {
+            from_timestamp: Some(100),
+            to_timestamp: Some(400),
+            kinds: Some(vec!["WalletDeployed".to_string()]),
+            caller: None,
+        };
+        let page = get_events_page(Some(0), None, Some(5), &filter);
+        let ids: Vec<_> = page.events.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![10, 12, 14, 16, 18]);
+        let page = get_events_page(page.next, None, Some(100), &filter);
+        let ids: Vec<_> = page.events.iter().map(|event| event.id).collect();
+        assert_eq!(ids, (20..40).step_by(2).collect::<Vec<_>>());
+        assert_eq!(page.next, None);
+
+        let latest = get_events_page(None, None, None, &EventFilter::default());
+        assert_eq!(latest.events.len(), 20);
+        assert_eq!(latest.events.last().unwrap().id, 50);
+        let earlier = get_events_page(None, latest.prev, Some(30), &EventFilter::default());
+        assert_eq!(earlier.events.first().unwrap().id, 1);
+        assert_eq!(earlier.events.last().unwrap().id, 30);
+        assert_eq!(earlier.prev, None);
 
-struct IdxMapVisitor;
+        let by_caller = EventFilter {
+            caller: Some(Principal::anonymous()),
+            ..Default::default()
+        };
+        let page = get_events_page(None, None, None, &by_caller);
+        let ids: Vec<_> = page.events.iter().map(|event| event.id).collect();
+        assert_eq!(ids, vec![49, 50]);
 
-impl<'de> Visitor<'de> for IdxMapVisitor {
-    type Value = ManagedList;
-    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
-        write!(formatter, "a sequence of `ManagedList` records")
+        // A zero limit still makes progress.
+        let page = get_events_page(Some(0), None, Some(0), &filter);
+        assert_eq!(page.events.len(), 1);
+        assert_eq!(page.next, Some(11));
     }
-    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
-    where
-        A: SeqAccess<'de>,
-    {
-        let mut map = IndexMap::with_capacity(seq.size_hint().unwrap_or(20));
-        while let Some(elem) = seq.next_element::<ManagedCanister>()? {
-            map.insert(elem.info.id, elem);
+
+    #[test]
+    fn chains_events() {
+        EVENT_BUFFER.with(|buffer| {
+            let mut buffer = buffer.borrow_mut();
+            for id in 1..=3 {
+                buffer.push(Event {
+                    id,
+                    timestamp: 0,
+                    kind: EventKind::AddressRemoved {
+                        id: Principal::anonymous(),
+                    },
+                    caller: None,
+                    method: None,
+                    prev_hash: None,
+                });
+            }
+        });
+        let raw = get_raw_events(Some(0), None, None);
+        assert_eq!(raw.len(), 3);
+        let events = get_events_page(Some(0), None, None, &EventFilter::default()).events;
+        assert!(events[0].prev_hash.is_none());
+        for i in 1..3 {
+            let hash = Sha256::digest(&raw[i - 1]);
+            assert_eq!(
+                events[i].prev_hash.as_ref().unwrap().as_slice(),
+                hash.as_slice()
+            );
         }
-        Ok(ManagedList(map))
+        assert_eq!(tip(), Some(Sha256::digest(&raw[2]).into()));
+    }
+
+    #[test]
+    fn tracks_controlled_canisters() {
+        let mut list = ManagedList::default();
+        let canister = Principal::anonymous();
+        assert!(!list.may_control(&canister));
+        let mut info = ManagedCanisterInfo {
+            id: canister,
+            name: None,
+            created_at: 0,
+            deleted_at: None,
+            controlled: None,
+        };
+        list.insert_info(info.clone());
+        assert!(list.may_control(&canister));
+        list.set_controlled(&canister, false);
+        assert!(!list.may_control(&canister));
+        list.set_controlled(&canister, true);
+        assert!(list.may_control(&canister));
+
+        info.controlled = Some(true);
+        info.deleted_at = Some(1);
+        list.insert_info(info);
+        assert!(!list.may_control(&canister));
+        assert_eq!(list.ids().count(), 1);
     }
 }
@@ -0,0 +1,187 @@
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha224};
+use std::cell::Cell;
+use std::convert::TryFrom;
+
+/// The ICP ledger on the IC mainnet.
+pub const ICP_LEDGER: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+/// The cycles minting canister on the IC mainnet.
+pub const CYCLES_MINTING_CANISTER: &str = "rkp4c-7iaaa-aaaaa-aaaaq-cai";
+/// The fee of an ICP ledger transfer, in e8s.
+pub const ICP_FEE: u64 = 10_000;
+/// The memo the cycles minting canister expects on top-up transfers ("TPUP").
+const MEMO_TOP_UP_CANISTER: u64 = 0x5055_5054;
+
+/// The ICP ledger and cycles minting canister used to convert ICP into cycles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct IcpCanisters {
+    pub ledger: Principal,
+    pub cycles_minting_canister: Principal,
+}
+
+thread_local! {
+    /// The canisters set with `set_icp_canisters`, if they aren't the mainnet ones.
+    pub static ICP_CANISTERS: Cell<Option<IcpCanisters>> = Cell::new(None);
+}
+
+/// The ICP ledger and cycles minting canister this wallet talks to.
+pub fn canisters() -> IcpCanisters {
+    ICP_CANISTERS
+        .with(|canisters| canisters.get())
+        .unwrap_or_else(|| IcpCanisters {
+            ledger: Principal::from_text(ICP_LEDGER).expect("Invalid ICP ledger ID."),
+            cycles_minting_canister: Principal::from_text(CYCLES_MINTING_CANISTER)
+                .expect("Invalid cycles minting canister ID."),
+        })
+}
+
+pub fn ledger_id() -> Principal {
+    canisters().ledger
+}
+
+pub fn cmc_id() -> Principal {
+    canisters().cycles_minting_canister
+}
+
+/// The 32-byte account identifier of `owner`'s `subaccount` on the ICP ledger.
+pub fn account_identifier(owner: &Principal, subaccount: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha224::new();
+    hasher.update(b"\x0Aaccount-id");
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount);
+    let hash = hasher.finalize();
+    let mut identifier = [0; 32];
+    identifier[..4].copy_from_slice(&crc32fast::hash(&hash).to_be_bytes());
+    identifier[4..].copy_from_slice(&hash);
+    identifier
+}
+
+/// The subaccount the cycles minting canister expects top-ups of `canister` to be sent to.
+fn top_up_subaccount(canister: &Principal) -> [u8; 32] {
+    let bytes = canister.as_slice();
+    let mut subaccount = [0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..=bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+struct Tokens {
+    e8s: u64,
+}
+
+#[derive(CandidType)]
+struct TransferArgs {
+    memo: u64,
+    amount: Tokens,
+    fee: Tokens,
+    from_subaccount: Option<ByteBuf>,
+    to: ByteBuf,
+    created_at_time: Option<TimeStamp>,
+}
+
+#[derive(CandidType)]
+struct TimeStamp {
+    timestamp_nanos: u64,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Tokens },
+    InsufficientFunds { balance: Tokens },
+    TxTooOld { allowed_window_nanos: u64 },
+    TxCreatedInFuture,
+    TxDuplicate { duplicate_of: u64 },
+}
+
+#[derive(CandidType)]
+struct NotifyTopUpArg {
+    block_index: u64,
+    canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum NotifyError {
+    Refunded {
+        reason: String,
+        block_index: Option<u64>,
+    },
+    Processing,
+    TransactionTooOld(u64),
+    InvalidTransaction(String),
+    Other {
+        error_code: u64,
+        error_message: String,
+    },
+}
+
+fn call_error(canister: &str, (code, msg): (api::call::RejectionCode, String)) -> String {
+    format!(
+        "An error happened during the call to the {}: {}: {}",
+        canister, code as u8, msg
+    )
+}
+
+/// Send `amount_e8s` of this wallet's ICP to the cycles minting canister, to be minted into cycles for
+/// `canister`. Returns the index of the transfer's block.
+pub async fn transfer_to_cmc(canister: &Principal, amount_e8s: u64) -> Result<u64, String> {
+    let to = account_identifier(&cmc_id(), &top_up_subaccount(canister));
+    let (result,): (Result<u64, TransferError>,) = api::call::call(
+        ledger_id(),
+        "transfer",
+        (TransferArgs {
+            memo: MEMO_TOP_UP_CANISTER,
+            amount: Tokens { e8s: amount_e8s },
+            fee: Tokens { e8s: ICP_FEE },
+            from_subaccount: None,
+            to: ByteBuf::from(to.to_vec()),
+            created_at_time: Some(TimeStamp {
+                timestamp_nanos: api::time(),
+            }),
+        },),
+    )
+    .await
+    .map_err(|err| call_error("ICP ledger", err))?;
+    result.map_err(|err| format!("The ICP ledger rejected the transfer: {:?}", err))
+}
+
+/// Have the cycles minting canister mint the ICP sent in block `block_index` into cycles for `canister`.
+/// Returns the number of cycles minted.
+pub async fn notify_top_up(canister: Principal, block_index: u64) -> Result<u128, String> {
+    let (result,): (Result<Nat, NotifyError>,) = api::call::call(
+        cmc_id(),
+        "notify_top_up",
+        (NotifyTopUpArg {
+            block_index,
+            canister_id: canister,
+        },),
+    )
+    .await
+    .map_err(|err| call_error("cycles minting canister", err))?;
+    result
+        .map(|cycles| u128::try_from(cycles.0).unwrap_or(u128::MAX))
+        .map_err(|err| {
+            format!(
+                "The cycles minting canister did not mint cycles for block {}: {:?}",
+                block_index, err
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::account_identifier;
+    use candid::Principal;
+
+    #[test]
+    fn computes_account_identifiers() {
+        // The anonymous principal's default account, as shown by `dfx ledger account-id`.
+        assert_eq!(
+            hex::encode(account_identifier(&Principal::anonymous(), &[0; 32])),
+            "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79"
+        );
+    }
+}
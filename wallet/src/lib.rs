@@ -2,32 +2,77 @@ use base64::Engine;
 use candid::{CandidType, Func, Principal, Reserved};
 use ic_cdk::api::{data_certificate, set_certified_data, trap};
 use ic_cdk::*;
-use ic_certified_map::{AsHashTree, Hash, RbTree};
+use ic_certified_map::{AsHashTree, Hash, HashTree, RbTree};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_bytes::{ByteBuf, Bytes};
 use sha2::Digest;
 use std::borrow::Cow;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::convert::TryInto;
 use std::mem;
 use std::thread::LocalKey;
+use std::time::Duration;
 
+mod access;
 mod address;
+mod alerts;
+mod burn;
+mod chart;
+mod cycles_ledger;
 mod events;
+mod icp;
+mod limits;
+mod memory;
 /// Migration functions to run on `#[post_upgrade]`.
 mod migrations;
-
-use crate::address::{AddressEntry, Role, ADDRESS_BOOK};
-use crate::events::{EventBuffer, ManagedCanisterEvent, ManagedCanisterEventKind, EVENT_BUFFER};
-use events::{record, Event, EventKind, ManagedList, MANAGED_LIST};
+mod proposals;
+mod schedule;
+mod spending;
+mod status;
+mod subscriptions;
+mod topup;
+
+use crate::access::{AccessRequest, AccessRequests, ACCESS_REQUESTS};
+use crate::address::{AddressEntry, CallPolicy, Role, ADDRESS_BOOK};
+use crate::alerts::{AlertStatus, AlertThreshold, Alerts, Notifier, ALERTS};
+use crate::cycles_ledger::{Account, CYCLES_LEDGER};
+use crate::events::{ManagedCanisterEvent, ManagedCanisterEventKind};
+use crate::icp::{IcpCanisters, ICP_CANISTERS};
+use crate::limits::{SpendingLimit, SpendingLimitStatus, SpendingLimits, SPENDING_LIMITS};
+use crate::memory::Memory;
+use crate::proposals::{MultisigPolicy, Operation, Proposal, ProposalStatus, Proposals, PROPOSALS};
+use crate::schedule::{PaymentSchedule, ScheduledPayment, Schedules, UpcomingPayment, SCHEDULES};
+use crate::subscriptions::{
+    PendingDelivery, SubscribeArgs, SubscriptionStatus, Subscriptions, SUBSCRIPTIONS,
+};
+use crate::topup::{TopUpPolicy, TopUpStatus, TopUps, TOP_UPS};
+use events::{record, Event, EventKind, MANAGED_LIST};
 
 const WALLET_API_VERSION: &str = env!("CARGO_PKG_VERSION");
 
-#[derive(Default)]
-struct WalletWASMBytes(Option<serde_bytes::ByteBuf>);
+/// The wallet wasm module used by `wallet_create_wallet`, kept in stable memory.
+struct WalletWASMBytes(Memory);
+
+impl Default for WalletWASMBytes {
+    fn default() -> Self {
+        Self(memory::get(memory::WALLET_WASM))
+    }
+}
+
+impl WalletWASMBytes {
+    fn get(&self) -> Option<Vec<u8>> {
+        memory::read_blob(&self.0).filter(|bytes| !bytes.is_empty())
+    }
+
+    fn set(&mut self, wasm_module: Option<&[u8]>) {
+        if let Err(err) = memory::write_blob(&mut self.0, wasm_module.unwrap_or_default()) {
+            trap(&err);
+        }
+    }
+}
 
 /// The wallet (this canister's) name.
 #[derive(Default)]
@@ -36,105 +81,124 @@ struct WalletName(pub(crate) Option<String>);
 thread_local! {
     static WALLET_NAME: RefCell<WalletName> = Default::default();
     static WALLET_WASM_BYTES: RefCell<WalletWASMBytes> = Default::default();
+    /// The cycles `wallet_send_batch` leaves in the wallet.
+    static BATCH_SEND_RESERVE: Cell<u128> = Cell::new(0);
 }
 
 /// Initialize this canister.
 #[init]
 fn init() {
     init_assets();
-    add_address(AddressEntry::new(caller(), None, Role::Controller));
+    insert_address("init", AddressEntry::new(caller(), None, Role::Controller));
+    start_timers();
 }
 
-/// Until the stable storage works better in the ic-cdk, this does the job just fine.
-#[derive(CandidType, Deserialize)]
-struct StableStorage {
-    address_book: Vec<AddressEntry>,
-    events: EventBuffer,
-    name: Option<String>,
-    chart: Vec<ChartTick>,
-    wasm_module: Option<serde_bytes::ByteBuf>,
-    managed: Option<ManagedList>,
+/// Arm the timers of the wallet's periodic tasks. Timers don't survive upgrades.
+fn start_timers() {
+    topup::start_timer();
+    schedule::start_timer();
+    alerts::start_timer();
+    subscriptions::start_timer();
+    ic_cdk_timers::set_timer_interval(CUSTODIAN_EXPIRY_INTERVAL, expire_custodians);
 }
 
-impl Default for StableStorage {
-    fn default() -> Self {
-        Self {
-            address_book: vec![],
-            chart: vec![],
-            events: Default::default(),
-            name: None,
-            wasm_module: None,
-            managed: Some(Default::default()),
-        }
-    }
+/// The state that isn't kept in stable structures. It is small enough to be saved as a single
+/// candid blob on upgrade; everything else lives in stable memory already.
+#[derive(CandidType, Default, Deserialize)]
+struct StableStorage {
+    name: Option<String>,
+    spending_limits: Option<SpendingLimits>,
+    proposals: Option<Proposals>,
+    top_ups: Option<TopUps>,
+    cycles_ledger: Option<Principal>,
+    icp_canisters: Option<IcpCanisters>,
+    schedules: Option<Schedules>,
+    batch_send_reserve: Option<u128>,
+    access_requests: Option<AccessRequests>,
+    alerts: Option<Alerts>,
+    subscriptions: Option<Subscriptions>,
 }
 
-const STABLE_VERSION: u32 = 2;
+const STABLE_VERSION: u32 = 4;
 
 #[pre_upgrade]
 fn pre_upgrade() {
     fn local_take<T: Default>(key: &'static LocalKey<RefCell<T>>) -> T {
         key.with(|cell| mem::take(&mut *cell.borrow_mut()))
     }
-    let address_book = local_take(&ADDRESS_BOOK);
     let stable = StableStorage {
-        address_book: address_book.iter().cloned().collect(),
-        events: local_take(&EVENT_BUFFER),
         name: local_take(&WALLET_NAME).0,
-        chart: local_take(&CHART_TICKS),
-        wasm_module: local_take(&WALLET_WASM_BYTES).0,
-        managed: Some(local_take(&MANAGED_LIST)),
-    };
-    match storage::stable_save((stable, Some(STABLE_VERSION))) {
-        Ok(_) => (),
-        Err(candid_err) => {
-            ic_cdk::trap(&format!(
-                "An error occurred when saving to stable memory (pre_upgrade): {}",
-                candid_err
-            ));
-        }
+        spending_limits: Some(local_take(&SPENDING_LIMITS)),
+        proposals: Some(local_take(&PROPOSALS)),
+        top_ups: Some(local_take(&TOP_UPS)),
+        cycles_ledger: CYCLES_LEDGER.with(|ledger| ledger.get()),
+        icp_canisters: ICP_CANISTERS.with(|canisters| canisters.get()),
+        schedules: Some(local_take(&SCHEDULES)),
+        batch_send_reserve: Some(BATCH_SEND_RESERVE.with(|reserve| reserve.get())),
+        access_requests: Some(local_take(&ACCESS_REQUESTS)),
+        alerts: Some(local_take(&ALERTS)),
+        subscriptions: Some(local_take(&SUBSCRIPTIONS)),
     };
+    let saved = candid::encode_args((stable, Some(STABLE_VERSION)))
+        .map_err(|candid_err| candid_err.to_string())
+        .and_then(|bytes| memory::write_blob(&mut memory::get(memory::UPGRADES), &bytes));
+    if let Err(err) = saved {
+        ic_cdk::trap(&format!(
+            "An error occurred when saving to stable memory (pre_upgrade): {}",
+            err
+        ));
+    }
 }
 
 #[post_upgrade]
 fn post_upgrade() {
-    init();
     let StableStorage {
-        address_book,
-        events,
         name,
-        chart,
-        wasm_module,
-        managed,
-    } = if let Ok((storage, Some(STABLE_VERSION))) =
-        storage::stable_restore::<(StableStorage, Option<u32>)>()
-    {
-        storage
-    } else if let Ok((_, version)) = storage::stable_restore::<(Reserved, Option<u32>)>() {
-        migrations::migrate_from(version.unwrap_or(1)).unwrap_or_default()
+        spending_limits,
+        proposals,
+        top_ups,
+        cycles_ledger,
+        icp_canisters,
+        schedules,
+        batch_send_reserve,
+        access_requests,
+        alerts,
+        subscriptions,
+    } = if memory::is_legacy_layout() {
+        migrations::migrate_legacy()
     } else {
-        return;
-    };
-    EVENT_BUFFER.with(|events0| *events0.borrow_mut() = events);
-    ADDRESS_BOOK.with(|address_book0| {
-        let mut address_book0 = address_book0.borrow_mut();
-        for entry in address_book.into_iter() {
-            address_book0.insert(entry)
-        }
-    });
-
-    WALLET_NAME.with(|name0| name0.borrow_mut().0 = name);
+        memory::read_blob(&memory::get(memory::UPGRADES))
+            .and_then(|bytes| candid::decode_args::<(StableStorage, Option<u32>)>(&bytes).ok())
+            .map(|(storage, _version)| storage)
+    }
+    .unwrap_or_default();
 
-    WALLET_WASM_BYTES.with(|bytes0| bytes0.borrow_mut().0 = wasm_module);
+    init_assets();
 
-    CHART_TICKS.with(|chart0| *chart0.borrow_mut() = chart);
-    MANAGED_LIST.with(|list0| *list0.borrow_mut() = managed.unwrap());
+    WALLET_NAME.with(|name0| name0.borrow_mut().0 = name);
+    SPENDING_LIMITS.with(|limits0| *limits0.borrow_mut() = spending_limits.unwrap_or_default());
+    PROPOSALS.with(|proposals0| *proposals0.borrow_mut() = proposals.unwrap_or_default());
+    TOP_UPS.with(|top_ups0| *top_ups0.borrow_mut() = top_ups.unwrap_or_default());
+    CYCLES_LEDGER.with(|ledger| ledger.set(cycles_ledger));
+    ICP_CANISTERS.with(|canisters| canisters.set(icp_canisters));
+    SCHEDULES.with(|schedules0| *schedules0.borrow_mut() = schedules.unwrap_or_default());
+    BATCH_SEND_RESERVE.with(|reserve| reserve.set(batch_send_reserve.unwrap_or_default()));
+    ACCESS_REQUESTS.with(|requests| *requests.borrow_mut() = access_requests.unwrap_or_default());
+    ALERTS.with(|alerts0| *alerts0.borrow_mut() = alerts.unwrap_or_default());
+    SUBSCRIPTIONS
+        .with(|subscriptions0| *subscriptions0.borrow_mut() = subscriptions.unwrap_or_default());
+    // Recorded once the subscriptions are restored, so that subscribers are told of it.
+    insert_address(
+        "post_upgrade",
+        AddressEntry::new(caller(), None, Role::Controller),
+    );
+    start_timers();
 }
 
 /***************************************************************************************************
  * Wallet API Version
  **************************************************************************************************/
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn wallet_api_version() -> String {
     WALLET_API_VERSION.to_string()
 }
@@ -142,7 +206,7 @@ fn wallet_api_version() -> String {
 /***************************************************************************************************
  * Wallet Name
  **************************************************************************************************/
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn name() -> Option<String> {
     WALLET_NAME.with(|name| name.borrow().0.clone())
 }
@@ -236,7 +300,10 @@ fn make_asset_certificate_header(asset_hashes: &AssetHashes, asset_name: &str) -
         trap("data certificate is only available in query calls");
     });
     let witness = asset_hashes.witness(asset_name.as_bytes());
-    let hash_tree = ic_certified_map::labeled(b"http_assets", witness);
+    let hash_tree = ic_certified_map::fork(
+        HashTree::Pruned(events_tree_hash()),
+        ic_certified_map::labeled(b"http_assets", witness),
+    );
     let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
     serializer.self_describe().unwrap();
     hash_tree.serialize(&mut serializer).unwrap();
@@ -393,10 +460,28 @@ fn init_assets() {
                 assets.contents.insert(name, (headers, contents));
             }
         });
-        let full_tree_hash =
-            ic_certified_map::labeled_hash(b"http_assets", &assets.hashes.root_hash());
-        set_certified_data(&full_tree_hash);
     });
+    update_certified_data();
+}
+
+/// Certify the HTTP assets and the tip of the event log.
+///
+/// The certified tree is `fork(labeled("events", leaf(tip)), labeled("http_assets", assets))`.
+fn update_certified_data() {
+    let assets_hash = ASSETS.with(|assets| assets_tree_hash(&assets.borrow().hashes));
+    set_certified_data(&ic_certified_map::fork_hash(
+        &events_tree_hash(),
+        &assets_hash,
+    ));
+}
+
+fn assets_tree_hash(asset_hashes: &AssetHashes) -> Hash {
+    ic_certified_map::labeled_hash(b"http_assets", &asset_hashes.root_hash())
+}
+
+fn events_tree_hash() -> Hash {
+    let tip = events::tip().unwrap_or_default();
+    ic_certified_map::labeled_hash(b"events", &ic_certified_map::leaf_hash(&tip))
 }
 
 /***************************************************************************************************
@@ -404,7 +489,7 @@ fn init_assets() {
  **************************************************************************************************/
 
 /// Get the controller of this canister.
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn get_controllers() -> Vec<Principal> {
     ADDRESS_BOOK.with(|book| book.borrow().controllers().map(|e| e.id).collect())
 }
@@ -412,13 +497,21 @@ fn get_controllers() -> Vec<Principal> {
 /// Set the controller (transfer of ownership).
 #[update(guard = "is_controller")]
 fn add_controller(controller: Principal) {
-    add_address(AddressEntry::new(controller, None, Role::Controller));
+    add_address_as(
+        "add_controller",
+        AddressEntry::new(controller, None, Role::Controller),
+    );
     update_chart();
 }
 
 /// Remove a controller. This is equivalent to moving the role to a regular user.
 #[update(guard = "is_controller")]
 fn remove_controller(controller: Principal) -> Result<(), String> {
+    proposals::check_direct("remove a controller")?;
+    demote_controller(controller)
+}
+
+fn demote_controller(controller: Principal) -> Result<(), String> {
     ADDRESS_BOOK.with(|book| {
         let mut book = book.borrow_mut();
         if !book.is_controller(&controller) {
@@ -445,7 +538,7 @@ fn remove_controller(controller: Principal) -> Result<(), String> {
  **************************************************************************************************/
 
 /// Get the custodians of this canister.
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn get_custodians() -> Vec<Principal> {
     ADDRESS_BOOK.with(|book| book.borrow().custodians().map(|e| e.id).collect())
 }
@@ -453,15 +546,61 @@ fn get_custodians() -> Vec<Principal> {
 /// Authorize a custodian.
 #[update(guard = "is_controller")]
 fn authorize(custodian: Principal) {
-    add_address(AddressEntry::new(custodian, None, Role::Custodian));
+    grant_custodian("authorize", custodian, None);
     update_chart();
 }
 
+/// Make `custodian` a custodian until `expires_at`, or for good, replacing the expiry of any earlier
+/// grant. Controllers stay controllers.
+fn grant_custodian(method: &str, custodian: Principal, expires_at: Option<u64>) {
+    add_address_as(method, AddressEntry::new(custodian, None, Role::Custodian));
+    ADDRESS_BOOK.with(|book| book.borrow_mut().set_expiry(&custodian, expires_at));
+}
+
+/// Authorize a custodian until `expires_at`, in nanoseconds since the epoch. The custodian is
+/// demoted to a contact once the grant lapses.
+#[update(guard = "is_controller")]
+fn authorize_until(custodian: Principal, expires_at: u64) -> Result<(), String> {
+    if expires_at <= api::time() {
+        return Err("Cannot authorize a custodian until a time in the past.".to_string());
+    }
+    if ADDRESS_BOOK.with(|book| book.borrow().is_controller(&custodian)) {
+        return Err(format!(
+            "Cannot authorize {} for a limited time as it is a controller.",
+            custodian.to_text()
+        ));
+    }
+    grant_custodian("authorize_until", custodian, Some(expires_at));
+    Ok(())
+}
+
+/// How often lapsed custodian grants are looked for.
+const CUSTODIAN_EXPIRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Demote the custodians whose grant lapsed to contacts.
+fn expire_custodians() {
+    let lapsed = ADDRESS_BOOK.with(|book| book.borrow_mut().demote_lapsed(api::time()));
+    for entry in &lapsed {
+        events::record_from(
+            api::id(),
+            "timer:custodian_expiry",
+            EventKind::AddressAdded {
+                id: entry.id,
+                name: entry.name.clone(),
+                role: Role::Contact,
+            },
+        );
+    }
+    if !lapsed.is_empty() {
+        update_chart();
+    }
+}
+
 /// Deauthorize a custodian.
 #[update(guard = "is_controller")]
 fn deauthorize(custodian: Principal) -> Result<(), String> {
     if ADDRESS_BOOK.with(|book| book.borrow().is_custodian(&custodian)) {
-        remove_address(custodian)?;
+        remove_address_as("deauthorize", custodian)?;
         update_chart();
         Ok(())
     } else {
@@ -472,29 +611,111 @@ fn deauthorize(custodian: Principal) -> Result<(), String> {
     }
 }
 
+/***************************************************************************************************
+ * Access Requests
+ **************************************************************************************************/
+
+/// Ask the controllers to make the caller a custodian. Returns the ID of the request.
+#[update]
+fn request_access(message: Option<String>) -> Result<u64, String> {
+    let requester = caller();
+    if requester == Principal::anonymous() {
+        return Err("The anonymous principal cannot request access.".to_string());
+    }
+    if ADDRESS_BOOK.with(|book| book.borrow().is_controller_or_custodian(&requester)) {
+        return Err("You already have access to this wallet.".to_string());
+    }
+    ACCESS_REQUESTS.with(|requests| {
+        requests
+            .borrow_mut()
+            .request(requester, message, api::time())
+    })
+}
+
+/// Get the pending access requests.
+#[query(guard = "is_controller")]
+fn list_access_requests() -> Vec<AccessRequest> {
+    ACCESS_REQUESTS.with(|requests| requests.borrow().list(api::time()))
+}
+
+/// Make the principal behind an access request a custodian, until `expires_at` if given.
+#[update(guard = "is_controller")]
+fn approve_access_request(id: u64, expires_at: Option<u64>) -> Result<(), String> {
+    if matches!(expires_at, Some(expires_at) if expires_at <= api::time()) {
+        return Err("Cannot authorize a custodian until a time in the past.".to_string());
+    }
+    let request = ACCESS_REQUESTS.with(|requests| requests.borrow_mut().take(id, api::time()))?;
+    grant_custodian("approve_access_request", request.requester, expires_at);
+    Ok(())
+}
+
+/// Turn down an access request.
+#[update(guard = "is_controller")]
+fn deny_access_request(id: u64) -> Result<(), String> {
+    ACCESS_REQUESTS.with(|requests| requests.borrow_mut().take(id, api::time()).map(|_| ()))
+}
+
+/***************************************************************************************************
+ * Spending Limits
+ **************************************************************************************************/
+
+/// Set (or with `None`, remove) the spending limit of a custodian.
+#[update(guard = "is_controller")]
+fn set_spending_limit(custodian: Principal, limit: Option<SpendingLimit>) {
+    SPENDING_LIMITS.with(|limits| limits.borrow_mut().set(custodian, limit));
+}
+
+/// Get the spending limit and remaining budget of a custodian, defaulting to the caller.
+/// Only controllers can look up the limits of other principals.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_spending_limit(custodian: Option<Principal>) -> Option<SpendingLimitStatus> {
+    let caller = caller();
+    let custodian = custodian.unwrap_or(caller);
+    if custodian != caller && is_controller().is_err() {
+        trap("Only the controller can view the spending limits of other principals.");
+    }
+    SPENDING_LIMITS.with(|limits| limits.borrow().status(&custodian, api::time()))
+}
+
+/// Set the number of cycles `wallet_send_batch` must leave in the wallet.
+#[update(guard = "is_controller")]
+fn set_batch_send_reserve(reserve: u128) {
+    BATCH_SEND_RESERVE.with(|reserve0| reserve0.set(reserve));
+}
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_batch_send_reserve() -> u128 {
+    BATCH_SEND_RESERVE.with(|reserve| reserve.get())
+}
+
 mod wallet {
-    use crate::{events, is_custodian_or_controller, WALLET_WASM_BYTES};
+    use crate::{
+        events, is_custodian_or_controller, is_viewer_custodian_or_controller, limits, proposals,
+        BATCH_SEND_RESERVE, WALLET_WASM_BYTES,
+    };
     use candid::{CandidType, Nat, Principal};
     use ic_cdk::*;
     use serde::Deserialize;
+    use serde_bytes::ByteBuf;
+    use sha2::Digest;
     use std::convert::TryInto;
 
     /***************************************************************************************************
      * Cycle Management
      **************************************************************************************************/
-    #[derive(CandidType)]
+    #[derive(CandidType, Deserialize)]
     struct BalanceResult<TCycles> {
         amount: TCycles,
     }
 
     #[derive(CandidType, Deserialize)]
-    struct SendCyclesArgs<TCycles> {
-        canister: Principal,
-        amount: TCycles,
+    pub(super) struct SendCyclesArgs<TCycles> {
+        pub(super) canister: Principal,
+        pub(super) amount: TCycles,
     }
 
     /// Return the cycle balance of this canister.
-    #[query(guard = "is_custodian_or_controller", name = "wallet_balance")]
+    #[query(guard = "is_viewer_custodian_or_controller", name = "wallet_balance")]
     fn balance() -> BalanceResult<u64> {
         BalanceResult {
             amount: api::canister_balance128()
@@ -503,7 +724,10 @@ mod wallet {
         }
     }
 
-    #[query(guard = "is_custodian_or_controller", name = "wallet_balance128")]
+    #[query(
+        guard = "is_viewer_custodian_or_controller",
+        name = "wallet_balance128"
+    )]
     fn balance128() -> BalanceResult<u128> {
         BalanceResult {
             amount: api::canister_balance128(),
@@ -518,14 +742,32 @@ mod wallet {
     /// Send cycles to another canister.
     #[update(guard = "is_custodian_or_controller", name = "wallet_send")]
     async fn send(SendCyclesArgs { canister, amount }: SendCyclesArgs<u64>) -> Result<(), String> {
-        send128(SendCyclesArgs {
-            canister,
-            amount: amount as u128,
-        })
+        proposals::check_direct_cycles(amount as u128)?;
+        send_cycles(
+            "wallet_send",
+            caller(),
+            SendCyclesArgs {
+                canister,
+                amount: amount as u128,
+            },
+        )
         .await
     }
     #[update(guard = "is_custodian_or_controller", name = "wallet_send128")]
     async fn send128(args: SendCyclesArgs<u128>) -> Result<(), String> {
+        proposals::check_direct_cycles(args.amount)?;
+        send_cycles("wallet_send128", caller(), args).await
+    }
+
+    /// Send cycles to another canister on behalf of `spender`, without checking whether the send
+    /// needs approval. The spender's call policy and spending limit apply, and the event names them.
+    pub(super) async fn send_cycles(
+        method: &str,
+        spender: Principal,
+        args: SendCyclesArgs<u128>,
+    ) -> Result<(), String> {
+        super::check_call_policy_of(spender, method, &args.canister, "deposit_cycles")?;
+        let reservation = limits::reserve(spender, args.amount)?;
         match api::call::call_with_payment128(
             Principal::management_canister(),
             "deposit_cycles",
@@ -538,21 +780,35 @@ mod wallet {
         {
             Ok(x) => {
                 let refund = api::call::msg_cycles_refunded128();
-                events::record(events::EventKind::CyclesSent {
-                    to: args.canister,
-                    amount: args.amount,
-                    refund,
-                });
+                limits::settle(reservation, args.amount.saturating_sub(refund));
+                events::record_from(
+                    spender,
+                    method,
+                    events::EventKind::CyclesSent {
+                        to: args.canister,
+                        amount: args.amount,
+                        refund,
+                        schedule: None,
+                        memo: None,
+                    },
+                );
                 super::update_chart();
                 x
             }
             Err((code, msg)) => {
                 let refund = api::call::msg_cycles_refunded128();
-                events::record(events::EventKind::CyclesSent {
-                    to: args.canister,
-                    amount: args.amount,
-                    refund,
-                });
+                limits::settle(reservation, args.amount.saturating_sub(refund));
+                events::record_from(
+                    spender,
+                    method,
+                    events::EventKind::CyclesSent {
+                        to: args.canister,
+                        amount: args.amount,
+                        refund,
+                        schedule: None,
+                        memo: None,
+                    },
+                );
                 let call_error =
                     format!("An error happened during the call: {}: {}", code as u8, msg);
                 let error = format!(
@@ -578,15 +834,106 @@ mod wallet {
         let amount = ic_cdk::api::call::msg_cycles_available128();
         if amount > 0 {
             let amount_accepted = ic_cdk::api::call::msg_cycles_accept128(amount);
-            events::record(events::EventKind::CyclesReceived {
-                from,
-                amount: amount_accepted,
-                memo: options.and_then(|opts| opts.memo),
-            });
+            events::record(
+                "wallet_receive",
+                events::EventKind::CyclesReceived {
+                    from,
+                    amount: amount_accepted,
+                    memo: options.and_then(|opts| opts.memo),
+                    source: None,
+                },
+            );
             super::update_chart();
         }
     }
 
+    /// The most entries `wallet_send_batch` and `wallet_call_batch` accept.
+    const MAX_BATCH_SIZE: usize = 100;
+
+    #[derive(CandidType, Deserialize)]
+    struct SendBatchEntry {
+        canister: Principal,
+        amount: u128,
+        memo: Option<String>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct SendBatchResult {
+        canister: Principal,
+        amount: u128,
+        refund: u128,
+        /// `None` if the cycles were sent.
+        error: Option<String>,
+    }
+
+    /// Send cycles to several canisters at once.
+    ///
+    /// Nothing is sent unless the total fits in the balance, minus the reserve set with
+    /// `set_batch_send_reserve`. The batch counts as a single operation against spending limits.
+    #[update(guard = "is_custodian_or_controller", name = "wallet_send_batch")]
+    async fn send_batch(entries: Vec<SendBatchEntry>) -> Result<Vec<SendBatchResult>, String> {
+        if entries.len() > MAX_BATCH_SIZE {
+            return Err(format!(
+                "A batch cannot have more than {} entries.",
+                MAX_BATCH_SIZE
+            ));
+        }
+        let total = entries
+            .iter()
+            .try_fold(0u128, |total, entry| total.checked_add(entry.amount))
+            .ok_or_else(|| "The total amount overflows.".to_string())?;
+        let reserve = BATCH_SEND_RESERVE.with(|reserve| reserve.get());
+        let available = api::canister_balance128().saturating_sub(reserve);
+        if total > available {
+            return Err(format!(
+                "Cannot send {} cycles: only {} are available above the reserve of {}.",
+                total, available, reserve
+            ));
+        }
+        for entry in &entries {
+            super::check_call_policy("wallet_send_batch", &entry.canister, "deposit_cycles")?;
+        }
+        proposals::check_direct_cycles(total)?;
+        let reservation = limits::reserve(caller(), total)?;
+
+        let results = futures::future::join_all(entries.into_iter().map(|entry| async move {
+            let result: Result<(), _> = api::call::call_with_payment128(
+                Principal::management_canister(),
+                "deposit_cycles",
+                (DepositCyclesArgs {
+                    canister_id: entry.canister,
+                },),
+                entry.amount,
+            )
+            .await;
+            let refund = api::call::msg_cycles_refunded128();
+            events::record(
+                "wallet_send_batch",
+                events::EventKind::CyclesSent {
+                    to: entry.canister,
+                    amount: entry.amount,
+                    refund,
+                    schedule: None,
+                    memo: entry.memo,
+                },
+            );
+            SendBatchResult {
+                canister: entry.canister,
+                amount: entry.amount,
+                refund,
+                error: result.err().map(|(code, msg)| {
+                    format!("An error happened during the call: {}: {}", code as u8, msg)
+                }),
+            }
+        }))
+        .await;
+
+        let refunded: u128 = results.iter().map(|result| result.refund).sum();
+        limits::settle(reservation, total.saturating_sub(refunded));
+        super::update_chart();
+        Ok(results)
+    }
+
     /***************************************************************************************************
      * Managing Canister
      **************************************************************************************************/
@@ -625,17 +972,25 @@ mod wallet {
     async fn create_canister(
         CreateCanisterArgs { cycles, settings }: CreateCanisterArgs<u64>,
     ) -> Result<CreateResult, String> {
-        create_canister128(CreateCanisterArgs {
-            cycles: cycles as u128,
-            settings,
-        })
+        create_canister_as(
+            "wallet_create_canister",
+            CreateCanisterArgs {
+                cycles: cycles as u128,
+                settings,
+            },
+        )
         .await
     }
     #[update(
         guard = "is_custodian_or_controller",
         name = "wallet_create_canister128"
     )]
-    async fn create_canister128(
+    async fn create_canister128(args: CreateCanisterArgs<u128>) -> Result<CreateResult, String> {
+        create_canister_as("wallet_create_canister128", args).await
+    }
+
+    async fn create_canister_as(
+        method: &str,
         mut args: CreateCanisterArgs<u128>,
     ) -> Result<CreateResult, String> {
         let mut settings = normalize_canister_settings(args.settings)?;
@@ -647,7 +1002,7 @@ mod wallet {
             controllers.push(ic_cdk::api::id());
         }
         args.settings = settings;
-        let create_result = create_canister_call(args).await?;
+        let create_result = create_canister_call(method, args).await?;
         super::update_chart();
         Ok(create_result)
     }
@@ -670,7 +1025,11 @@ mod wallet {
         }
     }
 
-    async fn create_canister_call(args: CreateCanisterArgs<u128>) -> Result<CreateResult, String> {
+    async fn create_canister_call(
+        method: &str,
+        args: CreateCanisterArgs<u128>,
+    ) -> Result<CreateResult, String> {
+        super::check_call_policy(method, &Principal::management_canister(), "create_canister")?;
         #[derive(CandidType)]
         struct In {
             settings: Option<CanisterSettings>,
@@ -679,14 +1038,20 @@ mod wallet {
             settings: Some(normalize_canister_settings(args.settings)?),
         };
 
-        let (create_result,): (CreateResult,) = match api::call::call_with_payment128(
+        let reservation = limits::reserve(caller(), args.cycles)?;
+        let result = api::call::call_with_payment128(
             Principal::management_canister(),
             "create_canister",
             (in_arg,),
             args.cycles,
         )
-        .await
-        {
+        .await;
+        limits::settle(
+            reservation,
+            args.cycles
+                .saturating_sub(api::call::msg_cycles_refunded128()),
+        );
+        let (create_result,): (CreateResult,) = match result {
             Ok(x) => x,
             Err((code, msg)) => {
                 return Err(format!(
@@ -696,10 +1061,13 @@ mod wallet {
             }
         };
 
-        events::record(events::EventKind::CanisterCreated {
-            canister: create_result.canister_id,
-            cycles: args.cycles,
-        });
+        events::record(
+            method,
+            events::EventKind::CanisterCreated {
+                canister: create_result.canister_id,
+                cycles: args.cycles,
+            },
+        );
         Ok(create_result)
     }
 
@@ -747,29 +1115,14 @@ mod wallet {
         Ok(())
     }
 
-    async fn install_wallet(canister_id: &Principal, wasm_module: Vec<u8>) -> Result<(), String> {
+    async fn install_wallet(
+        method: &str,
+        canister_id: &Principal,
+        wasm_module: Vec<u8>,
+    ) -> Result<(), String> {
         // Install Wasm
-        #[derive(CandidType, Deserialize)]
-        enum InstallMode {
-            #[serde(rename = "install")]
-            Install,
-            #[serde(rename = "reinstall")]
-            Reinstall,
-            #[serde(rename = "upgrade")]
-            Upgrade,
-        }
-
-        #[derive(CandidType, Deserialize)]
-        struct CanisterInstall {
-            mode: InstallMode,
-            canister_id: Principal,
-            #[serde(with = "serde_bytes")]
-            wasm_module: Vec<u8>,
-            arg: Vec<u8>,
-        }
-
         let install_config = CanisterInstall {
-            mode: InstallMode::Install,
+            mode: events::InstallMode::Install,
             canister_id: *canister_id,
             wasm_module: wasm_module.clone(),
             arg: b" ".to_vec(),
@@ -791,9 +1144,12 @@ mod wallet {
             }
         };
 
-        events::record(events::EventKind::WalletDeployed {
-            canister: *canister_id,
-        });
+        events::record(
+            method,
+            events::EventKind::WalletDeployed {
+                canister: *canister_id,
+            },
+        );
 
         // Store wallet wasm
         let store_args = WalletStoreWASMArgs { wasm_module };
@@ -813,20 +1169,31 @@ mod wallet {
     async fn create_wallet(
         CreateCanisterArgs { cycles, settings }: CreateCanisterArgs<u64>,
     ) -> Result<CreateResult, String> {
-        create_wallet128(CreateCanisterArgs {
-            cycles: cycles as u128,
-            settings,
-        })
+        create_wallet_as(
+            "wallet_create_wallet",
+            CreateCanisterArgs {
+                cycles: cycles as u128,
+                settings,
+            },
+        )
         .await
     }
     #[update(guard = "is_custodian_or_controller", name = "wallet_create_wallet128")]
     async fn create_wallet128(args: CreateCanisterArgs<u128>) -> Result<CreateResult, String> {
-        let wasm_module = WALLET_WASM_BYTES.with(|wallet_bytes| match &wallet_bytes.borrow().0 {
-            Some(o) => o.clone().into_vec(),
-            None => {
-                ic_cdk::trap("No wasm module stored.");
-            }
-        });
+        create_wallet_as("wallet_create_wallet128", args).await
+    }
+
+    async fn create_wallet_as(
+        method: &str,
+        args: CreateCanisterArgs<u128>,
+    ) -> Result<CreateResult, String> {
+        let wasm_module =
+            WALLET_WASM_BYTES.with(|wallet_bytes| match wallet_bytes.borrow().get() {
+                Some(o) => o,
+                None => {
+                    ic_cdk::trap("No wasm module stored.");
+                }
+            });
         let args_without_controller = CreateCanisterArgs {
             cycles: args.cycles,
             settings: CanisterSettings {
@@ -836,9 +1203,9 @@ mod wallet {
             },
         };
 
-        let create_result = create_canister_call(args_without_controller).await?;
+        let create_result = create_canister_call(method, args_without_controller).await?;
 
-        install_wallet(&create_result.canister_id, wasm_module).await?;
+        install_wallet(method, &create_result.canister_id, wasm_module).await?;
 
         // Set controller
         if args.settings.controller.is_some() || args.settings.controllers.is_some() {
@@ -863,9 +1230,11 @@ mod wallet {
 
     #[update(guard = "is_controller", name = "wallet_store_wallet_wasm")]
     async fn store_wallet_wasm(args: WalletStoreWASMArgs) {
-        WALLET_WASM_BYTES.with(|wallet_bytes| {
-            wallet_bytes.borrow_mut().0 = Some(serde_bytes::ByteBuf::from(args.wasm_module))
-        });
+        if let Err(err) = proposals::check_direct("store the wallet wasm") {
+            trap(&err);
+        }
+        WALLET_WASM_BYTES
+            .with(|wallet_bytes| wallet_bytes.borrow_mut().set(Some(&args.wasm_module)));
         super::update_chart();
     }
 
@@ -875,106 +1244,829 @@ mod wallet {
     }
 
     /***************************************************************************************************
-     * Call Forwarding
+     * Code Installation
      **************************************************************************************************/
     #[derive(CandidType, Deserialize)]
-    struct CallCanisterArgs<TCycles> {
-        canister: Principal,
-        method_name: String,
+    struct CanisterInstall {
+        mode: events::InstallMode,
+        canister_id: Principal,
         #[serde(with = "serde_bytes")]
-        args: Vec<u8>,
-        cycles: TCycles,
+        wasm_module: Vec<u8>,
+        #[serde(with = "serde_bytes")]
+        arg: Vec<u8>,
     }
 
     #[derive(CandidType, Deserialize)]
-    struct CallResult {
-        #[serde(with = "serde_bytes")]
-        r#return: Vec<u8>,
+    struct ChunkHash {
+        hash: ByteBuf,
     }
 
     #[derive(CandidType, Deserialize)]
-    struct CallResultWithMaxCycles {
-        #[serde(with = "serde_bytes")]
-        r#return: Vec<u8>,
-        attached_cycles: u128,
+    struct InstallChunkedCode {
+        mode: events::InstallMode,
+        target_canister: Principal,
+        store_canister: Option<Principal>,
+        chunk_hashes_list: Vec<ChunkHash>,
+        wasm_module_hash: ByteBuf,
+        arg: ByteBuf,
     }
 
-    /// Forward a call to another canister.
-    #[update(guard = "is_custodian_or_controller", name = "wallet_call")]
-    async fn call(
-        CallCanisterArgs {
-            canister,
-            method_name,
-            args,
-            cycles,
-        }: CallCanisterArgs<u64>,
-    ) -> Result<CallResult, String> {
-        call128(CallCanisterArgs {
-            canister,
-            method_name,
-            args,
-            cycles: cycles as u128,
-        })
-        .await
+    #[derive(CandidType, Deserialize)]
+    struct InstallCodeArgs {
+        canister: Principal,
+        mode: events::InstallMode,
+        /// The module to install. If `None`, the chunks uploaded with `wallet_upload_chunk` are installed.
+        wasm_module: Option<ByteBuf>,
+        /// The hashes of the uploaded chunks, in order, when installing uploaded chunks.
+        chunk_hashes: Option<Vec<ByteBuf>>,
+        /// The SHA-256 of the whole module, when installing uploaded chunks.
+        wasm_module_hash: Option<ByteBuf>,
+        arg: ByteBuf,
     }
 
-    #[update(guard = "is_custodian_or_controller", name = "wallet_call128")]
-    async fn call128(args: CallCanisterArgs<u128>) -> Result<CallResult, String> {
-        if api::id() == caller() {
-            return Err("Attempted to call forward on self. This is not allowed. Call this method via a different custodian.".to_string());
-        }
+    #[derive(CandidType, Deserialize)]
+    struct UploadChunkArgs {
+        canister: Principal,
+        chunk: ByteBuf,
+    }
 
-        match api::call::call_raw128(args.canister, &args.method_name, &args.args, args.cycles)
-            .await
-        {
-            Ok(x) => {
-                events::record(events::EventKind::CanisterCalled {
-                    canister: args.canister,
-                    method_name: args.method_name,
-                    cycles: args.cycles,
-                });
-                super::update_chart();
-                Ok(CallResult { r#return: x })
-            }
-            Err((code, msg)) => Err(format!(
-                "An error happened during the call: {}: {}",
-                code as u8, msg
+    #[derive(CandidType, Deserialize)]
+    struct ManagementUploadChunkArgs {
+        canister_id: Principal,
+        chunk: ByteBuf,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct CanisterIdRecord {
+        canister_id: Principal,
+    }
+
+    /// Check that `canister` is managed by this wallet and wasn't deleted.
+    pub(super) fn check_managed(canister: &Principal) -> Result<(), String> {
+        match super::MANAGED_LIST.with(|list| list.borrow().get(canister)) {
+            Some(info) if info.deleted_at.is_some() => Err(format!(
+                "{} was deleted by this wallet.",
+                canister.to_text()
+            )),
+            Some(_) => Ok(()),
+            None => Err(format!(
+                "{} is not a canister managed by this wallet.",
+                canister.to_text()
             )),
         }
     }
 
-    #[derive(CandidType, Deserialize)]
-    struct CallWithMaxCyclesArgs {
-        canister: Principal,
-        method_name: String,
-        args: Vec<u8>,
+    fn call_error((code, msg): (api::call::RejectionCode, String)) -> String {
+        format!("An error happened during the call: {}: {}", code as u8, msg)
+    }
+
+    /// Install, reinstall or upgrade the code of a managed canister.
+    #[update(guard = "is_custodian_or_controller", name = "wallet_install_code")]
+    async fn install_code(args: InstallCodeArgs) -> Result<(), String> {
+        check_managed(&args.canister)?;
+        super::check_call_policy(
+            "wallet_install_code",
+            &Principal::management_canister(),
+            "install_code",
+        )?;
+        let module_hash = match args.wasm_module {
+            Some(wasm_module) => {
+                let module_hash = sha2::Sha256::digest(&wasm_module).to_vec();
+                let install_config = CanisterInstall {
+                    mode: args.mode.clone(),
+                    canister_id: args.canister,
+                    wasm_module: wasm_module.into_vec(),
+                    arg: args.arg.into_vec(),
+                };
+                api::call::call::<_, ()>(
+                    Principal::management_canister(),
+                    "install_code",
+                    (install_config,),
+                )
+                .await
+                .map_err(call_error)?;
+                module_hash
+            }
+            None => {
+                let (chunk_hashes, wasm_module_hash) =
+                    match (args.chunk_hashes, args.wasm_module_hash) {
+                        (Some(chunk_hashes), Some(wasm_module_hash)) => {
+                            (chunk_hashes, wasm_module_hash)
+                        }
+                        _ => {
+                            return Err("Either `wasm_module`, or `chunk_hashes` and `wasm_module_hash`, must be set.".to_string())
+                        }
+                    };
+                let install_config = InstallChunkedCode {
+                    mode: args.mode.clone(),
+                    target_canister: args.canister,
+                    store_canister: None,
+                    chunk_hashes_list: chunk_hashes
+                        .into_iter()
+                        .map(|hash| ChunkHash { hash })
+                        .collect(),
+                    wasm_module_hash: wasm_module_hash.clone(),
+                    arg: args.arg,
+                };
+                api::call::call::<_, ()>(
+                    Principal::management_canister(),
+                    "install_chunked_code",
+                    (install_config,),
+                )
+                .await
+                .map_err(call_error)?;
+                wasm_module_hash.into_vec()
+            }
+        };
+        events::record(
+            "wallet_install_code",
+            events::EventKind::CodeInstalled {
+                canister: args.canister,
+                mode: args.mode,
+                module_hash: ByteBuf::from(module_hash),
+            },
+        );
+        super::update_chart();
+        Ok(())
+    }
+
+    /// Upload a chunk of a module to the chunk store of a managed canister, returning the chunk's hash.
+    #[update(guard = "is_custodian_or_controller", name = "wallet_upload_chunk")]
+    async fn upload_chunk(args: UploadChunkArgs) -> Result<ChunkHash, String> {
+        check_managed(&args.canister)?;
+        super::check_call_policy(
+            "wallet_upload_chunk",
+            &Principal::management_canister(),
+            "upload_chunk",
+        )?;
+        let (hash,) = api::call::call(
+            Principal::management_canister(),
+            "upload_chunk",
+            (ManagementUploadChunkArgs {
+                canister_id: args.canister,
+                chunk: args.chunk,
+            },),
+        )
+        .await
+        .map_err(call_error)?;
+        Ok(hash)
     }
 
+    /// Remove the chunks uploaded to a managed canister's chunk store.
     #[update(
         guard = "is_custodian_or_controller",
-        name = "wallet_call_with_max_cycles"
+        name = "wallet_clear_chunk_store"
     )]
-    async fn call_with_max_cycles(
-        args: CallWithMaxCyclesArgs,
-    ) -> Result<CallResultWithMaxCycles, String> {
-        let available_cycles = ic_cdk::api::canister_balance128();
-        // If no margin is used then the call either fails locally with `Couldn't send message` or processing the response traps with `Canister out of cycles`.
+    async fn clear_chunk_store(canister: Principal) -> Result<(), String> {
+        check_managed(&canister)?;
+        super::check_call_policy(
+            "wallet_clear_chunk_store",
+            &Principal::management_canister(),
+            "clear_chunk_store",
+        )?;
+        api::call::call(
+            Principal::management_canister(),
+            "clear_chunk_store",
+            (CanisterIdRecord {
+                canister_id: canister,
+            },),
+        )
+        .await
+        .map_err(call_error)
+    }
+
+    /***************************************************************************************************
+     * Canister Lifecycle
+     **************************************************************************************************/
+    /// The cycles left on a canister to pay for sending the rest of its balance back.
+    const RECLAIM_FEE: u128 = 10_000_000_000;
+
+    #[derive(CandidType, Deserialize)]
+    struct DeleteCanisterArgs {
+        canister: Principal,
+        /// Whether to withdraw the canister's cycles before deleting it. Defaults to `true`.
+        reclaim_cycles: Option<bool>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct DeleteResult {
+        cycles_reclaimed: u128,
+    }
+
+    async fn management_call(method: &str, canister: Principal) -> Result<(), String> {
+        api::call::call(
+            Principal::management_canister(),
+            method,
+            (CanisterIdRecord {
+                canister_id: canister,
+            },),
+        )
+        .await
+        .map_err(call_error)
+    }
+
+    async fn stop_canister_as(method: &str, canister: Principal) -> Result<(), String> {
+        management_call("stop_canister", canister).await?;
+        events::record(method, events::EventKind::CanisterStopped { canister });
+        Ok(())
+    }
+
+    async fn start_canister_as(method: &str, canister: Principal) -> Result<(), String> {
+        management_call("start_canister", canister).await?;
+        events::record(method, events::EventKind::CanisterStarted { canister });
+        Ok(())
+    }
+
+    /// Stop a managed canister.
+    #[update(guard = "is_custodian_or_controller", name = "wallet_stop_canister")]
+    async fn stop_canister(canister: Principal) -> Result<(), String> {
+        check_managed(&canister)?;
+        super::check_call_policy(
+            "wallet_stop_canister",
+            &Principal::management_canister(),
+            "stop_canister",
+        )?;
+        stop_canister_as("wallet_stop_canister", canister).await
+    }
+
+    /// Start a managed canister.
+    #[update(guard = "is_custodian_or_controller", name = "wallet_start_canister")]
+    async fn start_canister(canister: Principal) -> Result<(), String> {
+        check_managed(&canister)?;
+        super::check_call_policy(
+            "wallet_start_canister",
+            &Principal::management_canister(),
+            "start_canister",
+        )?;
+        start_canister_as("wallet_start_canister", canister).await
+    }
+
+    /// Delete a managed canister, withdrawing its cycles first unless told otherwise.
+    ///
+    /// The canister's events are kept, and it is marked as deleted in the managed canister list.
+    #[update(guard = "is_custodian_or_controller", name = "wallet_delete_canister")]
+    async fn delete_canister(args: DeleteCanisterArgs) -> Result<DeleteResult, String> {
+        const METHOD: &str = "wallet_delete_canister";
+        let canister = args.canister;
+        check_managed(&canister)?;
+        super::check_call_policy(METHOD, &Principal::management_canister(), "delete_canister")?;
+        let cycles_reclaimed = if args.reclaim_cycles.unwrap_or(true) {
+            reclaim_cycles(METHOD, canister).await?
+        } else {
+            0
+        };
+        stop_canister_as(METHOD, canister).await?;
+        management_call("delete_canister", canister).await?;
+        events::record(
+            METHOD,
+            events::EventKind::CanisterDeleted {
+                canister,
+                cycles_reclaimed,
+            },
+        );
+        super::TOP_UPS.with(|top_ups| top_ups.borrow_mut().set(canister, None));
+        super::ALERTS.with(|alerts| alerts.borrow_mut().set(canister, None));
+        super::status::STATUSES.with(|statuses| statuses.borrow_mut().remove(&canister));
+        super::update_chart();
+        Ok(DeleteResult { cycles_reclaimed })
+    }
+
+    /// Move the cycles of a canister back into this wallet, by replacing its code with the stored
+    /// wallet wasm and having it send its balance here. Returns the number of cycles sent.
+    async fn reclaim_cycles(method: &str, canister: Principal) -> Result<u128, String> {
+        let wasm_module = WALLET_WASM_BYTES
+            .with(|wallet_bytes| wallet_bytes.borrow().get())
+            .ok_or_else(|| {
+                "Reclaiming cycles needs a stored wallet wasm; delete with `reclaim_cycles = false` to burn them instead.".to_string()
+            })?;
+
+        // Let the canister spend every cycle it has.
+        update_settings_call(
+            UpdateSettingsArgs {
+                canister_id: canister,
+                settings: CanisterSettings {
+                    controller: None,
+                    controllers: None,
+                    compute_allocation: None,
+                    memory_allocation: None,
+                    freezing_threshold: Some(Nat::from(0u64)),
+                },
+            },
+            false,
+        )
+        .await?;
+
+        let module_hash = sha2::Sha256::digest(&wasm_module).to_vec();
+        api::call::call::<_, ()>(
+            Principal::management_canister(),
+            "install_code",
+            (CanisterInstall {
+                mode: events::InstallMode::Reinstall,
+                canister_id: canister,
+                wasm_module,
+                arg: candid::encode_args(()).expect("Could not encode the install arguments."),
+            },),
+        )
+        .await
+        .map_err(call_error)?;
+        events::record(
+            method,
+            events::EventKind::CodeInstalled {
+                canister,
+                mode: events::InstallMode::Reinstall,
+                module_hash: ByteBuf::from(module_hash),
+            },
+        );
+        // The canister must run to send its cycles; it is a no-op if it already does.
+        management_call("start_canister", canister).await?;
+
+        // The new wallet's controller is this wallet, which installed it.
+        let (BalanceResult::<u128> { amount: balance },) =
+            api::call::call(canister, "wallet_balance128", ())
+                .await
+                .map_err(call_error)?;
+        let amount = balance.saturating_sub(RECLAIM_FEE);
+        if amount == 0 {
+            return Ok(0);
+        }
+        let (result,): (Result<(), String>,) = api::call::call(
+            canister,
+            "wallet_send128",
+            (SendCyclesArgs {
+                canister: api::id(),
+                amount,
+            },),
+        )
+        .await
+        .map_err(call_error)?;
+        result.map(|()| amount)
+    }
+
+    /***************************************************************************************************
+     * Call Forwarding
+     **************************************************************************************************/
+    #[derive(CandidType, Deserialize)]
+    pub(super) struct CallCanisterArgs<TCycles> {
+        pub(super) canister: Principal,
+        pub(super) method_name: String,
+        #[serde(with = "serde_bytes")]
+        pub(super) args: Vec<u8>,
+        pub(super) cycles: TCycles,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub(super) struct CallResult {
+        #[serde(with = "serde_bytes")]
+        pub(super) r#return: Vec<u8>,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct CallResultWithMaxCycles {
+        #[serde(with = "serde_bytes")]
+        r#return: Vec<u8>,
+        attached_cycles: u128,
+    }
+
+    /// Forward a call to another canister.
+    #[update(guard = "is_custodian_or_controller", name = "wallet_call")]
+    async fn call(
+        CallCanisterArgs {
+            canister,
+            method_name,
+            args,
+            cycles,
+        }: CallCanisterArgs<u64>,
+    ) -> Result<CallResult, String> {
+        proposals::check_direct_cycles(cycles as u128)?;
+        forward_call(
+            "wallet_call",
+            caller(),
+            CallCanisterArgs {
+                canister,
+                method_name,
+                args,
+                cycles: cycles as u128,
+            },
+        )
+        .await
+    }
+
+    #[update(guard = "is_custodian_or_controller", name = "wallet_call128")]
+    async fn call128(args: CallCanisterArgs<u128>) -> Result<CallResult, String> {
+        proposals::check_direct_cycles(args.cycles)?;
+        forward_call("wallet_call128", caller(), args).await
+    }
+
+    /// Forward a call to another canister on behalf of `spender`, without checking whether the call
+    /// needs approval. The spender's call policy and spending limit apply, and the event names them.
+    pub(super) async fn forward_call(
+        method: &str,
+        spender: Principal,
+        args: CallCanisterArgs<u128>,
+    ) -> Result<CallResult, String> {
+        if api::id() == spender {
+            return Err("Attempted to call forward on self. This is not allowed. Call this method via a different custodian.".to_string());
+        }
+        super::check_call_policy_of(spender, method, &args.canister, &args.method_name)?;
+
+        let reservation = limits::reserve(spender, args.cycles)?;
+        let result =
+            api::call::call_raw128(args.canister, &args.method_name, &args.args, args.cycles).await;
+        let refund = api::call::msg_cycles_refunded128();
+        limits::settle(reservation, args.cycles.saturating_sub(refund));
+        match result {
+            Ok(x) => {
+                events::record_from(
+                    spender,
+                    method,
+                    events::EventKind::CanisterCalled {
+                        canister: args.canister,
+                        method_name: args.method_name,
+                        cycles: args.cycles,
+                        refund: Some(refund),
+                    },
+                );
+                super::update_chart();
+                Ok(CallResult { r#return: x })
+            }
+            Err((code, msg)) => Err(format!(
+                "An error happened during the call: {}: {}",
+                code as u8, msg
+            )),
+        }
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct CallWithMaxCyclesArgs {
+        canister: Principal,
+        method_name: String,
+        args: Vec<u8>,
+    }
+
+    #[update(
+        guard = "is_custodian_or_controller",
+        name = "wallet_call_with_max_cycles"
+    )]
+    async fn call_with_max_cycles(
+        args: CallWithMaxCyclesArgs,
+    ) -> Result<CallResultWithMaxCycles, String> {
+        let available_cycles = ic_cdk::api::canister_balance128();
+        // If no margin is used then the call either fails locally with `Couldn't send message` or processing the response traps with `Canister out of cycles`.
         // On the local network the margin needs to be ~1.7B cycles. (Experimentally determined in April 2024)
         // Extrapolating, a margin of 100B should work up to a subnet of ~60 nodes.
         const MARGIN: u128 = 100_000_000_000;
-        let cycles_to_attach = available_cycles.saturating_sub(MARGIN);
-        let result = call128(CallCanisterArgs {
-            canister: args.canister,
-            method_name: args.method_name,
-            args: args.args,
-            cycles: cycles_to_attach,
-        })
+        let mut cycles_to_attach = available_cycles.saturating_sub(MARGIN);
+        // Custodians with a spending limit can attach at most what remains of their allowance.
+        if let Some(remaining) = limits::remaining(&caller()) {
+            cycles_to_attach = cycles_to_attach.min(remaining);
+        }
+        proposals::check_direct_cycles(cycles_to_attach)?;
+        let result = forward_call(
+            "wallet_call_with_max_cycles",
+            caller(),
+            CallCanisterArgs {
+                canister: args.canister,
+                method_name: args.method_name,
+                args: args.args,
+                cycles: cycles_to_attach,
+            },
+        )
         .await?;
         Ok(CallResultWithMaxCycles {
             r#return: result.r#return,
             attached_cycles: cycles_to_attach,
         })
     }
+    #[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+    enum CallBatchMode {
+        /// One call after the other, stopping at the first error.
+        #[serde(rename = "sequential")]
+        Sequential,
+        /// All calls at once.
+        #[serde(rename = "parallel")]
+        Parallel,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    struct CallBatchArgs {
+        calls: Vec<CallCanisterArgs<u128>>,
+        /// Defaults to `Sequential`.
+        mode: Option<CallBatchMode>,
+    }
+
+    /// Forward several calls to other canisters.
+    ///
+    /// Returns the result of each call that was made, in order. In sequential mode, the calls after
+    /// the first error aren't made.
+    #[update(guard = "is_custodian_or_controller", name = "wallet_call_batch")]
+    async fn call_batch(args: CallBatchArgs) -> Result<Vec<Result<CallResult, String>>, String> {
+        const METHOD: &str = "wallet_call_batch";
+        if args.calls.len() > MAX_BATCH_SIZE {
+            return Err(format!(
+                "A batch cannot have more than {} calls.",
+                MAX_BATCH_SIZE
+            ));
+        }
+        let total = args
+            .calls
+            .iter()
+            .try_fold(0u128, |total, call| total.checked_add(call.cycles))
+            .ok_or_else(|| "The total amount of cycles overflows.".to_string())?;
+        proposals::check_direct_cycles(total)?;
+        match args.mode.unwrap_or(CallBatchMode::Sequential) {
+            CallBatchMode::Sequential => {
+                let mut results = Vec::with_capacity(args.calls.len());
+                for call in args.calls {
+                    let result = forward_call(METHOD, caller(), call).await;
+                    let failed = result.is_err();
+                    results.push(result);
+                    if failed {
+                        break;
+                    }
+                }
+                Ok(results)
+            }
+            CallBatchMode::Parallel => Ok(futures::future::join_all(
+                args.calls
+                    .into_iter()
+                    .map(|call| forward_call(METHOD, caller(), call)),
+            )
+            .await),
+        }
+    }
+}
+
+/***************************************************************************************************
+ * Multi-signature Proposals
+ **************************************************************************************************/
+
+#[derive(CandidType, Deserialize)]
+struct SubmitProposalArgs {
+    operation: Operation,
+    /// The module to store, for `StoreWalletWasm` proposals.
+    wasm_module: Option<ByteBuf>,
+}
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_multisig_policy() -> MultisigPolicy {
+    PROPOSALS.with(|proposals| proposals.borrow().policy.clone())
+}
+
+/// Set the multi-signature policy. Once its threshold is above 1, the policy can only be changed
+/// through a `SetPolicy` proposal.
+#[update(guard = "is_controller")]
+fn set_multisig_policy(policy: MultisigPolicy) -> Result<(), String> {
+    proposals::check_direct("change the multi-signature policy")?;
+    apply_multisig_policy(policy)
+}
+
+fn apply_multisig_policy(policy: MultisigPolicy) -> Result<(), String> {
+    let controllers = ADDRESS_BOOK.with(|book| book.borrow().controllers().count());
+    if policy.threshold == 0 || policy.threshold as usize > controllers {
+        return Err(format!(
+            "The threshold must be between 1 and the number of controllers ({}).",
+            controllers
+        ));
+    }
+    if policy.proposal_ttl == 0 {
+        return Err("The proposal TTL cannot be zero.".to_string());
+    }
+    PROPOSALS.with(|proposals| proposals.borrow_mut().policy = policy);
+    Ok(())
+}
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn list_proposals() -> Vec<Proposal> {
+    PROPOSALS.with(|proposals| proposals.borrow().iter().cloned().collect())
+}
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_proposal(id: u64) -> Option<Proposal> {
+    PROPOSALS.with(|proposals| proposals.borrow().get(id).cloned())
+}
+
+/// Propose an operation that needs the approval of several controllers. A proposal made by a
+/// controller counts as their approval.
+#[update(guard = "is_custodian_or_controller")]
+async fn submit_proposal(
+    SubmitProposalArgs {
+        operation,
+        wasm_module,
+    }: SubmitProposalArgs,
+) -> Result<Proposal, String> {
+    let proposer = caller();
+    let by_controller = is_controller().is_ok();
+    if !by_controller && !operation.custodian_may_propose() {
+        return Err("Only the controller can propose this operation.".to_string());
+    }
+    match &operation {
+        Operation::SendCycles { canister, .. } => {
+            check_call_policy("submit_proposal", canister, "deposit_cycles")?
+        }
+        Operation::CallCanister {
+            canister,
+            method_name,
+            ..
+        } => check_call_policy("submit_proposal", canister, method_name)?,
+        _ => (),
+    }
+    match (&operation, &wasm_module) {
+        (Operation::StoreWalletWasm { wasm_module_hash }, Some(wasm_module)) => {
+            if sha2::Sha256::digest(wasm_module).as_slice() != wasm_module_hash.as_slice() {
+                return Err("The wasm module does not match `wasm_module_hash`.".to_string());
+            }
+        }
+        (Operation::StoreWalletWasm { .. }, None) => {
+            return Err("A `StoreWalletWasm` proposal needs a `wasm_module`.".to_string())
+        }
+        (_, Some(_)) => {
+            return Err("Only `StoreWalletWasm` proposals take a `wasm_module`.".to_string())
+        }
+        (_, None) => (),
+    }
+    expire_proposals("submit_proposal");
+    let id = PROPOSALS.with(|proposals| {
+        proposals
+            .borrow_mut()
+            .submit(proposer, operation, wasm_module, api::time())
+    })?;
+    record(
+        "submit_proposal",
+        EventKind::ProposalSubmitted { id, proposer },
+    );
+    if by_controller {
+        vote("submit_proposal", id, true).await
+    } else {
+        Ok(PROPOSALS.with(|proposals| proposals.borrow().get(id).cloned().unwrap()))
+    }
+}
+
+/// Approve a proposal. It is executed as soon as enough controllers have approved it.
+#[update(guard = "is_controller")]
+async fn approve_proposal(id: u64) -> Result<Proposal, String> {
+    expire_proposals("approve_proposal");
+    vote("approve_proposal", id, true).await
+}
+
+/// Reject a proposal. It is closed as soon as it can no longer get enough approvals.
+#[update(guard = "is_controller")]
+async fn reject_proposal(id: u64) -> Result<Proposal, String> {
+    expire_proposals("reject_proposal");
+    vote("reject_proposal", id, false).await
+}
+
+async fn vote(method: &str, id: u64, approve: bool) -> Result<Proposal, String> {
+    let controller = caller();
+    let controllers: Vec<Principal> =
+        ADDRESS_BOOK.with(|book| book.borrow().controllers().map(|e| e.id).collect());
+    let approved = PROPOSALS.with(|proposals| {
+        let mut proposals = proposals.borrow_mut();
+        let required = proposals.required_approvals(controllers.len());
+        let proposal = proposals.get_open_mut(id)?;
+        if proposal.approvals.contains(&controller) || proposal.rejections.contains(&controller) {
+            return Err(format!(
+                "{} has already voted on proposal {}.",
+                controller.to_text(),
+                id
+            ));
+        }
+        if approve {
+            proposal.approvals.push(controller);
+        } else {
+            proposal.rejections.push(controller);
+        }
+        // Votes from principals that have since stopped being controllers don't count.
+        let count = |votes: &[Principal]| votes.iter().filter(|v| controllers.contains(v)).count();
+        if count(&proposal.approvals) >= required {
+            proposal.status = ProposalStatus::Executing;
+            Ok(Some((proposal.proposer, proposal.operation.clone())))
+        } else {
+            if controllers.len() - count(&proposal.rejections) < required {
+                proposals.close(id, ProposalStatus::Rejected);
+            }
+            Ok(None)
+        }
+    })?;
+    if approve {
+        record(method, EventKind::ProposalApproved { id, controller });
+    } else {
+        record(method, EventKind::ProposalRejected { id, controller });
+    }
+    if let Some((proposer, operation)) = approved {
+        let _guard = ExecutionGuard {
+            method: method.to_string(),
+            id,
+        };
+        execute_proposal(method, id, proposer, operation).await;
+    }
+    Ok(PROPOSALS.with(|proposals| proposals.borrow().get(id).cloned().unwrap()))
+}
+
+/// Fails a proposal left executing when its execution ends, as happens if a callback traps after
+/// the state change that started it was committed.
+struct ExecutionGuard {
+    method: String,
+    id: u64,
+}
+
+impl Drop for ExecutionGuard {
+    fn drop(&mut self) {
+        let executing = PROPOSALS.with(|proposals| {
+            matches!(
+                proposals.borrow().get(self.id),
+                Some(Proposal {
+                    status: ProposalStatus::Executing,
+                    ..
+                })
+            )
+        });
+        if executing {
+            let error = "The execution of the proposal trapped.".to_string();
+            PROPOSALS.with(|proposals| {
+                proposals.borrow_mut().close(
+                    self.id,
+                    ProposalStatus::Failed {
+                        error: error.clone(),
+                    },
+                )
+            });
+            record(
+                &self.method,
+                EventKind::ProposalExecuted {
+                    id: self.id,
+                    error: Some(error),
+                },
+            );
+        }
+    }
+}
+
+/// Carry out an approved proposal. Cycles are spent on behalf of the `proposer`, so their call
+/// policy and spending limit apply when the proposal runs.
+async fn execute_proposal(method: &str, id: u64, proposer: Principal, operation: Operation) {
+    let result = match operation {
+        Operation::SendCycles { canister, amount } => wallet::send_cycles(
+            method,
+            proposer,
+            wallet::SendCyclesArgs { canister, amount },
+        )
+        .await
+        .map(|()| None),
+        Operation::CallCanister {
+            canister,
+            method_name,
+            args,
+            cycles,
+        } => wallet::forward_call(
+            method,
+            proposer,
+            wallet::CallCanisterArgs {
+                canister,
+                method_name,
+                args: args.into_vec(),
+                cycles,
+            },
+        )
+        .await
+        .map(|result| Some(ByteBuf::from(result.r#return))),
+        Operation::AddController { controller } => {
+            insert_address(
+                method,
+                AddressEntry::new(controller, None, Role::Controller),
+            );
+            Ok(None)
+        }
+        Operation::RemoveController { controller } => demote_controller(controller).map(|()| None),
+        Operation::StoreWalletWasm { .. } => {
+            let wasm_module =
+                PROPOSALS.with(|proposals| proposals.borrow_mut().take_wasm_module(id));
+            WALLET_WASM_BYTES.with(|wallet_bytes| {
+                wallet_bytes
+                    .borrow_mut()
+                    .set(wasm_module.as_ref().map(|bytes| bytes.as_slice()))
+            });
+            update_chart();
+            Ok(None)
+        }
+        Operation::SetPolicy { policy } => apply_multisig_policy(policy).map(|()| None),
+    };
+    let (status, error) = match result {
+        Ok(reply) => (ProposalStatus::Executed { reply }, None),
+        Err(error) => (
+            ProposalStatus::Failed {
+                error: error.clone(),
+            },
+            Some(error),
+        ),
+    };
+    PROPOSALS.with(|proposals| proposals.borrow_mut().close(id, status));
+    record(method, EventKind::ProposalExecuted { id, error });
+}
+
+fn expire_proposals(method: &str) {
+    for id in PROPOSALS.with(|proposals| proposals.borrow_mut().expire(api::time())) {
+        record(method, EventKind::ProposalExpired { id });
+    }
 }
 
 /***************************************************************************************************
@@ -984,65 +2076,193 @@ mod wallet {
 // Address book
 #[update(guard = "is_controller")]
 fn add_address(address: AddressEntry) {
+    // Only `authorize_until` and `approve_access_request` grant custodians for a limited time.
+    add_address_as(
+        "add_address",
+        AddressEntry {
+            expires_at: None,
+            ..address
+        },
+    );
+}
+
+/// Add an address through the wallet API method `method`, unless doing so needs a proposal.
+fn add_address_as(method: &str, address: AddressEntry) {
+    if address.is_controller()
+        && !ADDRESS_BOOK.with(|book| book.borrow().is_controller(&address.id))
+    {
+        if let Err(err) = proposals::check_direct("add a controller") {
+            trap(&err);
+        }
+    }
+    insert_address(method, address);
+}
+
+fn insert_address(method: &str, address: AddressEntry) {
     ADDRESS_BOOK.with(|book| book.borrow_mut().insert(address.clone()));
-    record(EventKind::AddressAdded {
-        id: address.id,
-        name: address.name,
-        role: address.role,
-    });
+    record(
+        method,
+        EventKind::AddressAdded {
+            id: address.id,
+            name: address.name,
+            role: address.role,
+        },
+    );
     update_chart();
 }
 
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn list_addresses() -> Vec<AddressEntry> {
-    ADDRESS_BOOK.with(|book| book.borrow().iter().cloned().collect())
+    ADDRESS_BOOK.with(|book| book.borrow().iter().collect())
 }
 
 #[update(guard = "is_controller")]
 fn remove_address(address: Principal) -> Result<(), String> {
+    remove_address_as("remove_address", address)
+}
+
+fn remove_address_as(method: &str, address: Principal) -> Result<(), String> {
+    if ADDRESS_BOOK.with(|book| book.borrow().is_controller(&address)) {
+        proposals::check_direct("remove a controller")?;
+    }
     ADDRESS_BOOK.with(|book| {
         let mut book = book.borrow_mut();
         if book.is_controller(&address) && book.controllers().count() == 1 {
             Err("The wallet must have at least one controller.".to_string())
         } else {
             book.remove(&address);
-            record(EventKind::AddressRemoved { id: address });
+            record(method, EventKind::AddressRemoved { id: address });
             update_chart();
             Ok(())
         }
     })
 }
+
+/// Restrict the calls a custodian can make through the wallet, or lift the restriction with `None`.
+#[update(guard = "is_controller")]
+fn set_call_policy(address: Principal, policy: Option<CallPolicy>) -> Result<(), String> {
+    ADDRESS_BOOK.with(|book| book.borrow_mut().set_call_policy(&address, policy))
+}
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_call_policy(address: Principal) -> Option<CallPolicy> {
+    ADDRESS_BOOK.with(|book| book.borrow().find(&address)?.call_policy)
+}
+
 /***************************************************************************************************
  * Events
  **************************************************************************************************/
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Default, Deserialize)]
 struct GetEventsArgs {
     from: Option<u32>,
     to: Option<u32>,
+    /// Only return events recorded at or after this time, in nanoseconds since the epoch.
+    from_timestamp: Option<u64>,
+    /// Only return events recorded before this time, in nanoseconds since the epoch.
+    to_timestamp: Option<u64>,
+    /// Only return events of these kinds, e.g. `"CyclesSent"`.
+    kinds: Option<Vec<String>>,
+    /// The maximum number of events to return.
+    limit: Option<u32>,
 }
 
 /// Return the recent events observed by this canister.
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn get_events128(args: Option<GetEventsArgs>) -> Vec<Event> {
-    if let Some(GetEventsArgs { from, to }) = args {
-        events::get_events(from, to)
-    } else {
-        events::get_events(None, None)
+    get_events_page(args).events
+}
+
+/// Return a page of the event log, along with the cursor of the next page.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_events_page(args: Option<GetEventsArgs>) -> events::EventPage {
+    let GetEventsArgs {
+        from,
+        to,
+        from_timestamp,
+        to_timestamp,
+        kinds,
+        limit,
+    } = args.unwrap_or_default();
+    let filter = events::EventFilter {
+        from_timestamp,
+        to_timestamp,
+        kinds,
+        caller: None,
+    };
+    events::get_events_page(from, to, limit, &filter)
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetEventsByCallerArgs {
+    caller: Principal,
+    from: Option<u32>,
+    to: Option<u32>,
+    limit: Option<u32>,
+}
+
+/// Return a page of the events caused by a given principal, paged like `get_events_page`.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_events_by_caller(args: GetEventsByCallerArgs) -> events::EventPage {
+    let filter = events::EventFilter {
+        caller: Some(args.caller),
+        ..Default::default()
+    };
+    events::get_events_page(args.from, args.to, args.limit, &filter)
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetCertifiedEventsArgs {
+    from: Option<u32>,
+    to: Option<u32>,
+    limit: Option<u32>,
+}
+
+#[derive(CandidType)]
+struct CertifiedEvents {
+    /// The candid-encoded events, oldest first. Each event's `prev_hash` is the SHA-256 of the one before it.
+    events: Vec<ByteBuf>,
+    /// The certificate of this canister's certified data.
+    certificate: ByteBuf,
+    /// The CBOR-encoded hash tree, revealing the SHA-256 of the last recorded event under `events`.
+    witness: ByteBuf,
+}
+
+/// Return events as they were hashed into the event log, along with a certificate of the log's tip.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_certified_events(args: Option<GetCertifiedEventsArgs>) -> CertifiedEvents {
+    let certificate = data_certificate().unwrap_or_else(|| {
+        trap("data certificate is only available in query calls");
+    });
+    let (from, to, limit) =
+        args.map_or((None, None, None), |args| (args.from, args.to, args.limit));
+    let tip = events::tip().unwrap_or_default();
+    let hash_tree = ic_certified_map::fork(
+        ic_certified_map::labeled(b"events", HashTree::Leaf(Cow::Borrowed(&tip))),
+        HashTree::Pruned(ASSETS.with(|assets| assets_tree_hash(&assets.borrow().hashes))),
+    );
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    hash_tree.serialize(&mut serializer).unwrap();
+    CertifiedEvents {
+        events: events::get_raw_events(from, to, limit),
+        certificate: ByteBuf::from(certificate),
+        witness: ByteBuf::from(serializer.into_inner()),
     }
 }
 
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn get_events(args: Option<GetEventsArgs>) -> Vec<migrations::v1::V1Event> {
     use migrations::v1::*;
     let events = get_events128(args);
     events
         .into_iter()
-        .map(
+        .filter_map(
             |Event {
                  id,
                  timestamp,
                  kind,
+                 ..
              }| {
                 let kind = match kind {
                     EventKind::AddressAdded { id, name, role } => {
@@ -1053,6 +2273,7 @@ fn get_events(args: Option<GetEventsArgs>) -> Vec<migrations::v1::V1Event> {
                         canister,
                         cycles,
                         method_name,
+                        ..
                     } => V1EventKind::CanisterCalled {
                         canister,
                         cycles: cycles.try_into().expect("`CanisterCalled` event exceeded a 64-bit cycle count; call `get_events128`"),
@@ -1064,14 +2285,18 @@ fn get_events(args: Option<GetEventsArgs>) -> Vec<migrations::v1::V1Event> {
                             cycles: cycles.try_into().expect("`CanisterCreated` event exceeded a 64-bit cycle count; call `get_events128`"),
                         }
                     }
-                    EventKind::CyclesReceived { amount, from, memo } => {
+                    EventKind::CyclesReceived {
+                        amount, from, memo, ..
+                    } => {
                         V1EventKind::CyclesReceived {
                             amount: amount.try_into().expect("`CyclesReceived` event exceeded a 64-bit cycle count; call `get_events128`"),
                             from,
                             memo,
                         }
                     }
-                    EventKind::CyclesSent { amount, refund, to } => V1EventKind::CyclesSent {
+                    EventKind::CyclesSent {
+                        amount, refund, to, ..
+                    } => V1EventKind::CyclesSent {
                         amount: amount.try_into().expect("`CyclesSent` event exceeded a 64-bit `amount` cycle count; call `get_events128`"),
                         refund: refund.try_into().expect("`CyclesSent` event exceeded a 64-bit `refund` cycle count; call `get_events128`"),
                         to,
@@ -1079,12 +2304,14 @@ fn get_events(args: Option<GetEventsArgs>) -> Vec<migrations::v1::V1Event> {
                     EventKind::WalletDeployed { canister } => {
                         V1EventKind::WalletDeployed { canister }
                     }
+                    // Newer kinds of events cannot be represented by the 64-bit API.
+                    _ => return None,
                 };
-                V1Event {
+                Some(V1Event {
                     id,
                     timestamp,
                     kind,
-                }
+                })
             },
         )
         .collect()
@@ -1100,7 +2327,7 @@ struct ListCanistersArgs {
     to: Option<u32>,
 }
 
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn list_managed_canisters(args: ListCanistersArgs) -> (Vec<events::ManagedCanisterInfo>, u32) {
     events::get_managed_canisters(args.from, args.to)
 }
@@ -1112,14 +2339,14 @@ struct GetManagedCanisterEventArgs {
     to: Option<u32>,
 }
 
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn get_managed_canister_events128(
     args: GetManagedCanisterEventArgs,
 ) -> Option<Vec<events::ManagedCanisterEvent>> {
     events::get_managed_canister_events(&args.canister, args.from, args.to)
 }
 
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn get_managed_canister_events(
     args: GetManagedCanisterEventArgs,
 ) -> Option<Vec<migrations::v1::V1ManagedCanisterEvent>> {
@@ -1128,7 +2355,7 @@ fn get_managed_canister_events(
     events.map(|events| {
         events
             .into_iter()
-            .map(
+            .filter_map(
                 |ManagedCanisterEvent {
                      id,
                      timestamp,
@@ -1138,6 +2365,7 @@ fn get_managed_canister_events(
                         ManagedCanisterEventKind::Called {
                             cycles,
                             method_name,
+                            ..
                         } => V1ManagedCanisterEventKind::Called {
                             cycles: cycles.try_into().expect("`Called` event exceeded a 64-bit cycle count; call `get_managed_canister_events128`"),
                             method_name,
@@ -1153,18 +2381,62 @@ fn get_managed_canister_events(
                                 refund: refund.try_into().expect("`CyclesSent` event exceeded a 64-bit `refund` cycle count; call `get_managed_canister_events128`"),
                             }
                         }
+                        // Newer kinds of events cannot be represented by the 64-bit API.
+                        _ => return None,
                     };
-                    V1ManagedCanisterEvent {
+                    Some(V1ManagedCanisterEvent {
                         id,
                         timestamp,
                         kind,
-                    }
+                    })
                 },
             )
             .collect()
     })
 }
 
+#[derive(CandidType, Deserialize)]
+struct RefreshStatusesArgs {
+    /// The canisters to refresh. Defaults to every managed canister the wallet may control.
+    canisters: Option<Vec<Principal>>,
+}
+
+/// The most statuses fetched at the same time by `refresh_managed_canister_statuses`.
+const REFRESH_BATCH_SIZE: usize = 10;
+
+/// Fetch the status of managed canisters from the management canister, and cache it.
+///
+/// By default, canisters the wallet deleted or was seen not to control are skipped.
+#[update(guard = "is_custodian_or_controller")]
+async fn refresh_managed_canister_statuses(
+    args: Option<RefreshStatusesArgs>,
+) -> Vec<status::ManagedCanisterStatus> {
+    let canisters: Vec<Principal> = MANAGED_LIST.with(|list| {
+        let list = list.borrow();
+        match args.and_then(|args| args.canisters) {
+            Some(canisters) => canisters
+                .into_iter()
+                .filter(|canister| list.get(canister).is_some())
+                .collect(),
+            None => list
+                .ids()
+                .filter(|canister| list.may_control(canister))
+                .collect(),
+        }
+    });
+    for batch in canisters.chunks(REFRESH_BATCH_SIZE) {
+        // Failures are cached along with the last known status.
+        futures::future::join_all(batch.iter().map(|canister| status::fetch(*canister))).await;
+    }
+    status::get(canisters.into_iter())
+}
+
+/// Return the cached status of every managed canister that was refreshed at least once.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_managed_canister_statuses() -> Vec<status::ManagedCanisterStatus> {
+    MANAGED_LIST.with(|list| status::get(list.borrow().ids()))
+}
+
 #[update(guard = "is_custodian_or_controller")]
 fn set_short_name(
     canister: Principal,
@@ -1174,66 +2446,642 @@ fn set_short_name(
 }
 
 /***************************************************************************************************
- * Charts
+ * Managed Canister Top-ups
  **************************************************************************************************/
-#[derive(Clone, CandidType, Deserialize)]
-pub struct ChartTick {
-    timestamp: u64,
-    cycles: u64,
+
+/// Set or remove the policy used to keep a managed canister funded.
+///
+/// The wallet must be a controller of the canister to read its balance.
+#[update(guard = "is_controller")]
+fn set_top_up_policy(canister: Principal, policy: Option<TopUpPolicy>) -> Result<(), String> {
+    if let Some(policy) = &policy {
+        wallet::check_managed(&canister)?;
+        topup::validate(policy)?;
+        proposals::check_direct_cycles(policy.max_cycles_per_day)?;
+    }
+    TOP_UPS.with(|top_ups| top_ups.borrow_mut().set(canister, policy));
+    Ok(())
 }
 
-thread_local! {
-    static CHART_TICKS: RefCell<Vec<ChartTick>> = Default::default();
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_top_up_policies() -> Vec<TopUpStatus> {
+    TOP_UPS.with(|top_ups| top_ups.borrow().statuses(api::time()))
+}
+
+/***************************************************************************************************
+ * Low-balance Alerts
+ **************************************************************************************************/
+
+/// Set or remove the canister method low-balance alerts are sent to. No alerts are sent without one.
+#[update(guard = "is_controller")]
+fn set_alert_notifier(notifier: Option<Notifier>) -> Result<(), String> {
+    if matches!(&notifier, Some(notifier) if notifier.method.is_empty()) {
+        return Err("The notifier method cannot be empty.".to_string());
+    }
+    ALERTS.with(|alerts| alerts.borrow_mut().set_notifier(notifier));
+    Ok(())
+}
+
+/// Set or remove the low-balance alert threshold of a managed canister, or of the wallet itself
+/// if no canister is given.
+///
+/// The wallet must be a controller of the canister to read its balance.
+#[update(guard = "is_controller")]
+fn set_balance_alert(
+    canister: Option<Principal>,
+    threshold: Option<AlertThreshold>,
+) -> Result<(), String> {
+    if let Some(threshold) = &threshold {
+        if let Some(canister) = &canister {
+            wallet::check_managed(canister)?;
+        }
+        alerts::validate(threshold)?;
+    }
+    let canister = canister.unwrap_or_else(api::id);
+    ALERTS.with(|alerts| alerts.borrow_mut().set(canister, threshold));
+    Ok(())
 }
 
+#[derive(CandidType, Deserialize)]
+struct BalanceAlerts {
+    notifier: Option<Notifier>,
+    alerts: Vec<AlertStatus>,
+}
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_balance_alerts() -> BalanceAlerts {
+    ALERTS.with(|alerts| {
+        let alerts = alerts.borrow();
+        BalanceAlerts {
+            notifier: alerts.notifier(),
+            alerts: alerts.statuses(),
+        }
+    })
+}
+
+/***************************************************************************************************
+ * Event Subscriptions
+ **************************************************************************************************/
+
+/// Have the wallet call a canister method with each new event of the given kinds. Returns the ID
+/// of the subscription.
+///
+/// Events are delivered with one-way calls, so a delivery counts as soon as the call is made and a
+/// subscriber that traps or doesn't reply misses the event. Calls that can't be made are retried, for
+/// up to 10 attempts.
+#[update(guard = "is_controller")]
+fn subscribe(args: SubscribeArgs) -> Result<u64, String> {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().add(args))
+}
+
+/// Remove a subscription, dropping its pending deliveries.
+#[update(guard = "is_controller")]
+fn unsubscribe(id: u64) -> Result<(), String> {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow_mut().remove(id))
+}
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_subscriptions() -> Vec<SubscriptionStatus> {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().statuses())
+}
+
+/// The deliveries still waiting, of a single subscription if given.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_pending_deliveries(subscription: Option<u64>) -> Vec<PendingDelivery> {
+    SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().pending(subscription))
+}
+
+/***************************************************************************************************
+ * Scheduled Payments
+ **************************************************************************************************/
+
+/// Schedule a one-off or recurring payment. Returns the ID of the schedule.
+///
+/// Payments are made by a timer within a minute of falling due. Payments missed while the wallet
+/// was stopped are skipped.
+#[update(guard = "is_controller")]
+fn schedule_payment(schedule: PaymentSchedule) -> Result<u64, String> {
+    proposals::check_direct_cycles(schedule.amount)?;
+    SCHEDULES.with(|schedules| schedules.borrow_mut().add(schedule))
+}
+
+#[update(guard = "is_controller")]
+fn pause_scheduled_payment(id: u64) -> Result<(), String> {
+    SCHEDULES.with(|schedules| schedules.borrow_mut().set_paused(id, true))
+}
+
+#[update(guard = "is_controller")]
+fn resume_scheduled_payment(id: u64) -> Result<(), String> {
+    SCHEDULES.with(|schedules| schedules.borrow_mut().set_paused(id, false))
+}
+
+#[update(guard = "is_controller")]
+fn cancel_scheduled_payment(id: u64) -> Result<(), String> {
+    SCHEDULES.with(|schedules| schedules.borrow_mut().cancel(id))
+}
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn list_scheduled_payments() -> Vec<ScheduledPayment> {
+    SCHEDULES.with(|schedules| schedules.borrow().list())
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetUpcomingPaymentsArgs {
+    until: Option<u64>,
+    limit: Option<u32>,
+}
+
+/// The payments due until `until` (default: any time), soonest first. At most `limit` (default 20,
+/// maximum 1,000) are returned.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_upcoming_payments(args: Option<GetUpcomingPaymentsArgs>) -> Vec<UpcomingPayment> {
+    let (until, limit) = args.map_or((None, None), |args| (args.until, args.limit));
+    let limit = limit.unwrap_or(20).min(1_000) as usize;
+    SCHEDULES.with(|schedules| {
+        schedules
+            .borrow()
+            .upcoming(until.unwrap_or(u64::MAX), limit)
+    })
+}
+
+/***************************************************************************************************
+ * Cycles Ledger
+ **************************************************************************************************/
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_cycles_ledger() -> Principal {
+    cycles_ledger::id()
+}
+
+/// Use another cycles ledger than the mainnet one, e.g. one deployed locally. `None` restores the default.
+#[update(guard = "is_controller")]
+fn set_cycles_ledger(ledger: Option<Principal>) -> Result<(), String> {
+    proposals::check_direct("change the cycles ledger")?;
+    CYCLES_LEDGER.with(|ledger0| ledger0.set(ledger));
+    Ok(())
+}
+
+#[derive(CandidType, Deserialize)]
+struct CyclesLedgerDepositArgs {
+    /// Defaults to the wallet's own account.
+    to: Option<Account>,
+    amount: u128,
+    memo: Option<ByteBuf>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct CyclesLedgerDepositResult {
+    balance: u128,
+    block_index: u64,
+}
+
+/// Deposit some of the wallet's cycles into a cycles ledger account.
+///
+/// Depositing into an account the wallet doesn't own is subject to the caller's call policy, as
+/// calling `deposit` on the cycles ledger and sending cycles to the account's owner.
+#[update(guard = "is_custodian_or_controller")]
+async fn wallet_cycles_ledger_deposit(
+    args: CyclesLedgerDepositArgs,
+) -> Result<CyclesLedgerDepositResult, String> {
+    let to = args.to.unwrap_or_else(Account::wallet);
+    for (canister, method_name) in
+        cycles_ledger::deposit_permissions(&to, &api::id(), cycles_ledger::id())
+    {
+        check_call_policy("wallet_cycles_ledger_deposit", &canister, method_name)?;
+    }
+    proposals::check_direct_cycles(args.amount)?;
+    let reservation = limits::reserve(caller(), args.amount)?;
+    match cycles_ledger::deposit(to.clone(), args.memo, args.amount).await {
+        Ok(deposit) => {
+            limits::settle(reservation, args.amount.saturating_sub(deposit.refund));
+            record(
+                "wallet_cycles_ledger_deposit",
+                EventKind::CyclesLedgerDeposit {
+                    to,
+                    amount: args.amount,
+                    refund: deposit.refund,
+                    block_index: deposit.block_index,
+                },
+            );
+            update_chart();
+            Ok(CyclesLedgerDepositResult {
+                balance: deposit.balance,
+                block_index: deposit.block_index,
+            })
+        }
+        Err((err, refund)) => {
+            limits::settle(reservation, args.amount.saturating_sub(refund));
+            Err(err)
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize)]
+struct CyclesLedgerWithdrawArgs {
+    from_subaccount: Option<ByteBuf>,
+    /// Defaults to the wallet itself.
+    to: Option<Principal>,
+    amount: u128,
+}
+
+/// Withdraw cycles from one of the wallet's cycles ledger accounts into the wallet or another canister.
+/// Returns the block index of the withdrawal.
+#[update(guard = "is_custodian_or_controller")]
+async fn wallet_cycles_ledger_withdraw(args: CyclesLedgerWithdrawArgs) -> Result<u64, String> {
+    let to = args.to.unwrap_or_else(api::id);
+    // Withdrawing into the wallet itself doesn't spend anything.
+    let reservation = if to == api::id() {
+        None
+    } else {
+        check_call_policy("wallet_cycles_ledger_withdraw", &to, "deposit_cycles")?;
+        proposals::check_direct_cycles(args.amount)?;
+        limits::reserve(caller(), args.amount)?
+    };
+    let result = cycles_ledger::withdraw(args.from_subaccount.clone(), to, args.amount).await;
+    limits::settle(reservation, if result.is_ok() { args.amount } else { 0 });
+    let block_index = result?;
+    record(
+        "wallet_cycles_ledger_withdraw",
+        EventKind::CyclesLedgerWithdrawal {
+            from_subaccount: args.from_subaccount,
+            to,
+            amount: args.amount,
+            block_index,
+        },
+    );
+    update_chart();
+    Ok(block_index)
+}
+
+#[derive(CandidType, Deserialize)]
+struct CyclesLedgerApproveArgs {
+    from_subaccount: Option<ByteBuf>,
+    spender: Account,
+    amount: u128,
+    expires_at: Option<u64>,
+}
+
+/// Let `spender` transfer cycles from one of the wallet's cycles ledger accounts (ICRC-2).
+/// Returns the block index of the approval.
+#[update(guard = "is_controller")]
+async fn wallet_cycles_ledger_approve(args: CyclesLedgerApproveArgs) -> Result<u64, String> {
+    proposals::check_direct_cycles(args.amount)?;
+    let block_index = cycles_ledger::approve(
+        args.from_subaccount.clone(),
+        args.spender.clone(),
+        args.amount,
+        args.expires_at,
+    )
+    .await?;
+    record(
+        "wallet_cycles_ledger_approve",
+        EventKind::CyclesLedgerApproval {
+            from_subaccount: args.from_subaccount,
+            spender: args.spender,
+            amount: args.amount,
+            expires_at: args.expires_at,
+            block_index,
+        },
+    );
+    Ok(block_index)
+}
+
+/// The cycles ledger balance of one of the wallet's accounts.
+#[update(guard = "is_custodian_or_controller")]
+async fn wallet_cycles_ledger_balance(subaccount: Option<ByteBuf>) -> Result<u128, String> {
+    cycles_ledger::balance_of(Account {
+        owner: api::id(),
+        subaccount,
+    })
+    .await
+}
+
+/***************************************************************************************************
+ * ICP Conversion
+ **************************************************************************************************/
+
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_icp_canisters() -> IcpCanisters {
+    icp::canisters()
+}
+
+/// Use another ICP ledger and cycles minting canister than the mainnet ones, e.g. ones deployed
+/// locally. `None` restores the defaults.
+#[update(guard = "is_controller")]
+fn set_icp_canisters(canisters: Option<IcpCanisters>) -> Result<(), String> {
+    proposals::check_direct("change the ICP canisters")?;
+    ICP_CANISTERS.with(|canisters0| canisters0.set(canisters));
+    Ok(())
+}
+
+/// The hex-encoded ICP ledger account identifier of this wallet's default account.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn wallet_account_identifier() -> String {
+    hex::encode(icp::account_identifier(&api::id(), &[0; 32]))
+}
+
+#[derive(CandidType, Deserialize)]
+struct TopUpWithIcpArgs {
+    amount_e8s: u64,
+    /// The managed canister to mint cycles for. Defaults to the wallet itself.
+    canister: Option<Principal>,
+    /// The block of an earlier transfer to the cycles minting canister whose notification failed.
+    /// If set, no ICP is transferred and `amount_e8s` is only used to record the event.
+    block_index: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TopUpWithIcpResult {
+    cycles: u128,
+    block_index: u64,
+}
+
+/// Convert ICP from the wallet's account into cycles for the wallet or one of its managed canisters,
+/// through the cycles minting canister.
+#[update(guard = "is_controller")]
+async fn wallet_top_up_with_icp(args: TopUpWithIcpArgs) -> Result<TopUpWithIcpResult, String> {
+    if let Some(canister) = &args.canister {
+        wallet::check_managed(canister)?;
+    }
+    let recipient = args.canister.unwrap_or_else(api::id);
+    let block_index = match args.block_index {
+        Some(block_index) => block_index,
+        None => icp::transfer_to_cmc(&recipient, args.amount_e8s).await?,
+    };
+    let cycles = icp::notify_top_up(recipient, block_index)
+        .await
+        .map_err(|err| {
+            format!(
+                "{}\nRetry with `block_index = {}` once the problem is solved.",
+                err, block_index
+            )
+        })?;
+    record(
+        "wallet_top_up_with_icp",
+        EventKind::CyclesReceived {
+            from: icp::cmc_id(),
+            amount: cycles,
+            memo: None,
+            source: Some(events::CyclesSource::IcpConversion {
+                icp_e8s: args.amount_e8s,
+                block_index,
+                canister: args.canister,
+            }),
+        },
+    );
+    update_chart();
+    Ok(TopUpWithIcpResult {
+        cycles,
+        block_index,
+    })
+}
+
+/***************************************************************************************************
+ * Charts
+ **************************************************************************************************/
 #[derive(CandidType, Deserialize)]
 struct GetChartArgs {
     count: Option<u32>,
     precision: Option<u64>,
 }
 
-#[query(guard = "is_custodian_or_controller")]
+#[query(guard = "is_viewer_custodian_or_controller")]
 fn get_chart(args: Option<GetChartArgs>) -> Vec<(u64, u64)> {
-    CHART_TICKS.with(|chart| {
-        let chart = chart.borrow();
+    let GetChartArgs { count, precision } = args.unwrap_or(GetChartArgs {
+        count: None,
+        precision: None,
+    });
+    let take = count.unwrap_or(100).min(chart::MAX_BUCKETS as u32);
+    // Precision is in nanoseconds. This is an hour.
+    let precision = precision.unwrap_or(60 * 60 * 1_000_000);
+
+    let mut last_tick = u64::MAX;
+    chart::get(
+        chart::Resolution::for_precision(precision),
+        0,
+        u64::MAX,
+        usize::MAX,
+    )
+    .into_iter()
+    .rev()
+    .filter(|bucket| {
+        if bucket.start >= last_tick {
+            false
+        } else {
+            last_tick = bucket.start.saturating_sub(precision);
+            true
+        }
+    })
+    .take(take as usize)
+    .map(|bucket| {
+        (
+            bucket.start,
+            u64::try_from(bucket.close).unwrap_or(u64::MAX),
+        )
+    })
+    .collect()
+}
 
-        let GetChartArgs { count, precision } = args.unwrap_or(GetChartArgs {
-            count: None,
-            precision: None,
-        });
-        let take = count.unwrap_or(100).max(1000);
-        // Precision is in nanoseconds. This is an hour.
-        let precision = precision.unwrap_or(60 * 60 * 1_000_000);
+#[derive(CandidType, Deserialize)]
+struct GetChart128Args {
+    /// Defaults to an hour.
+    resolution: Option<chart::Resolution>,
+    /// Only buckets starting at or after this time, in nanoseconds since the epoch.
+    from: Option<u64>,
+    /// Only buckets starting at or before this time.
+    to: Option<u64>,
+    /// The number of buckets to return, counting back from `to`. Defaults to 100, and is capped at 1000.
+    limit: Option<u32>,
+}
 
-        let mut last_tick = u64::MAX;
-        #[allow(clippy::unnecessary_filter_map)]
-        chart
-            .iter()
-            .rev()
-            .filter_map(|tick| {
-                if tick.timestamp >= last_tick {
-                    None
-                } else {
-                    last_tick = tick.timestamp - precision;
-                    Some(tick)
-                }
-            })
-            .take(take as usize)
-            .map(|tick| (tick.timestamp, tick.cycles))
-            .collect()
-    })
+/// The wallet's balance over time, oldest first.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_chart128(args: Option<GetChart128Args>) -> Vec<chart::ChartBucket> {
+    let GetChart128Args {
+        resolution,
+        from,
+        to,
+        limit,
+    } = args.unwrap_or(GetChart128Args {
+        resolution: None,
+        from: None,
+        to: None,
+        limit: None,
+    });
+    chart::get(
+        resolution.unwrap_or(chart::Resolution::Hour),
+        from.unwrap_or(0),
+        to.unwrap_or(u64::MAX),
+        limit
+            .map_or(100, |limit| limit as usize)
+            .min(chart::MAX_BUCKETS),
+    )
 }
 
 fn update_chart() {
-    let timestamp = api::time();
-    let cycles = api::canister_balance();
-    CHART_TICKS.with(|chart| chart.borrow_mut().push(ChartTick { timestamp, cycles }));
+    chart::record(api::time(), api::canister_balance128());
+}
+
+/***************************************************************************************************
+ * Burn Rate
+ **************************************************************************************************/
+
+#[derive(CandidType, Deserialize)]
+struct GetBurnRateArgs {
+    /// The period to average over, in nanoseconds. Defaults to a week, and is capped at 90 days.
+    period: Option<u64>,
+    /// The index in the managed canister list to start from, as returned in `next`.
+    from: Option<u32>,
+    /// The most canisters to return. Defaults to 20, and is capped at 50.
+    limit: Option<u32>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct BurnRateReport {
+    wallet: burn::BurnRate,
+    canisters: Vec<burn::CanisterBurnRate>,
+    /// Where to continue the canister list from, if there are more canisters.
+    next: Option<u32>,
+}
+
+/// How fast the wallet and the managed canisters it controls burn cycles, and how long their balances
+/// will last.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_burn_rate(args: Option<GetBurnRateArgs>) -> BurnRateReport {
+    let GetBurnRateArgs {
+        period,
+        from,
+        limit,
+    } = args.unwrap_or(GetBurnRateArgs {
+        period: None,
+        from: None,
+        limit: None,
+    });
+    let period = period.unwrap_or(7 * 24 * 60 * 60 * 1_000_000_000);
+    let limit = limit
+        .map_or(20, |limit| limit as usize)
+        .clamp(1, burn::MAX_CANISTERS);
+    let (canisters, next) = burn::canisters(period, from.unwrap_or(0), limit);
+    BurnRateReport {
+        wallet: burn::wallet(period),
+        canisters,
+        next,
+    }
+}
+
+/***************************************************************************************************
+ * Spending Reports
+ **************************************************************************************************/
+
+#[derive(CandidType, Deserialize)]
+struct GetSpendingReportArgs {
+    /// In nanoseconds since the epoch. Defaults to 30 days before `to`.
+    from: Option<u64>,
+    /// Defaults to now.
+    to: Option<u64>,
+    /// The `next` of a report that was cut short, to continue it.
+    start_event: Option<u32>,
+}
+
+/// The cycles the wallet spent over a period, grouped by destination, method, caller and name.
+#[query(guard = "is_viewer_custodian_or_controller")]
+fn get_spending_report(args: Option<GetSpendingReportArgs>) -> spending::SpendingReport {
+    let (from, to, start_event) = args.map_or((None, None, None), |args| {
+        (args.from, args.to, args.start_event)
+    });
+    let to = to.unwrap_or_else(api::time);
+    let from = from.unwrap_or_else(|| to.saturating_sub(30 * 24 * 60 * 60 * 1_000_000_000));
+    if from > to {
+        return spending::SpendingReport {
+            from,
+            to,
+            ..Default::default()
+        };
+    }
+    spending::get(from, to, start_event)
 }
 
 /***************************************************************************************************
  * Utilities
  **************************************************************************************************/
 
+/// The largest argument accepted for most methods, in bytes.
+const MAX_ARG_SIZE: usize = 64 * 1024;
+/// The largest argument accepted for methods that carry a module or forwarded arguments, in bytes.
+/// That's as large as an ingress message can be.
+const MAX_LARGE_ARG_SIZE: usize = 2 * 1024 * 1024;
+
+/// Drop ingress messages that the method's guard or argument size would reject anyway, before they
+/// are accepted into a block and charged to the wallet.
+///
+/// Methods that aren't listed below only need the caller to be a viewer. Their own guard still
+/// runs when they execute, so this can only ever be more lenient than the guards.
+#[inspect_message]
+fn inspect_message() {
+    let method = api::call::method_name();
+    let (check, max_arg_size) = match method.as_str() {
+        "wallet_receive" | "request_access" | "http_request" => (Ok(()), MAX_ARG_SIZE),
+        "wallet_store_wallet_wasm" => (is_controller(), MAX_LARGE_ARG_SIZE),
+        "set_name"
+        | "add_controller"
+        | "remove_controller"
+        | "authorize"
+        | "authorize_until"
+        | "deauthorize"
+        | "list_access_requests"
+        | "approve_access_request"
+        | "deny_access_request"
+        | "set_spending_limit"
+        | "set_batch_send_reserve"
+        | "set_multisig_policy"
+        | "approve_proposal"
+        | "reject_proposal"
+        | "add_address"
+        | "remove_address"
+        | "set_call_policy"
+        | "set_top_up_policy"
+        | "set_alert_notifier"
+        | "set_balance_alert"
+        | "subscribe"
+        | "unsubscribe"
+        | "schedule_payment"
+        | "pause_scheduled_payment"
+        | "resume_scheduled_payment"
+        | "cancel_scheduled_payment"
+        | "set_cycles_ledger"
+        | "set_icp_canisters"
+        | "wallet_cycles_ledger_approve"
+        | "wallet_top_up_with_icp" => (is_controller(), MAX_ARG_SIZE),
+        "wallet_install_code"
+        | "wallet_upload_chunk"
+        | "wallet_call"
+        | "wallet_call128"
+        | "wallet_call_with_max_cycles"
+        | "wallet_call_batch"
+        | "submit_proposal" => (is_custodian_or_controller(), MAX_LARGE_ARG_SIZE),
+        "wallet_send"
+        | "wallet_send128"
+        | "wallet_send_batch"
+        | "wallet_create_canister"
+        | "wallet_create_canister128"
+        | "wallet_create_wallet"
+        | "wallet_create_wallet128"
+        | "wallet_clear_chunk_store"
+        | "wallet_stop_canister"
+        | "wallet_start_canister"
+        | "wallet_delete_canister"
+        | "refresh_managed_canister_statuses"
+        | "set_short_name"
+        | "wallet_cycles_ledger_deposit"
+        | "wallet_cycles_ledger_withdraw"
+        | "wallet_cycles_ledger_balance" => (is_custodian_or_controller(), MAX_ARG_SIZE),
+        _ => (is_viewer_custodian_or_controller(), MAX_ARG_SIZE),
+    };
+    if check.is_ok() && api::call::arg_data_raw_size() <= max_arg_size {
+        api::call::accept_message();
+    }
+}
+
 /// Check if the caller is the initializer.
 fn is_controller() -> Result<(), String> {
     if ADDRESS_BOOK.with(|book| book.borrow().is_controller(&caller())) {
@@ -1243,12 +3091,59 @@ fn is_controller() -> Result<(), String> {
     }
 }
 
+/// Fail if the caller's call policy doesn't let them call `method_name` on `canister`, recording
+/// the attempt through the wallet API method `method`.
+fn check_call_policy(method: &str, canister: &Principal, method_name: &str) -> Result<(), String> {
+    check_call_policy_of(caller(), method, canister, method_name)
+}
+
+/// Fail if the call policy of `caller`, on whose behalf the wallet acts, doesn't allow calling
+/// `method_name` on `canister`.
+fn check_call_policy_of(
+    caller: Principal,
+    method: &str,
+    canister: &Principal,
+    method_name: &str,
+) -> Result<(), String> {
+    if ADDRESS_BOOK.with(|book| book.borrow().may_call(&caller, canister, method_name)) {
+        return Ok(());
+    }
+    events::record_from(
+        caller,
+        method,
+        EventKind::CallDenied {
+            canister: *canister,
+            method_name: method_name.to_string(),
+        },
+    );
+    Err(format!(
+        "The call policy of {} does not allow calling `{}` on {}.",
+        caller, method_name, canister
+    ))
+}
+
+/// Check if the caller may read the wallet's state: a viewer, custodian or controller.
+fn is_viewer_custodian_or_controller() -> Result<(), String> {
+    let caller = &caller();
+    let allowed = ADDRESS_BOOK.with(|book| {
+        let book = book.borrow();
+        book.is_viewer_custodian_or_controller(caller) && !book.has_lapsed(caller, api::time())
+    });
+    if allowed || &api::id() == caller {
+        Ok(())
+    } else {
+        Err("Only a controller, custodian or viewer can call this method.".to_string())
+    }
+}
+
 /// Check if the caller is a custodian.
 fn is_custodian_or_controller() -> Result<(), String> {
     let caller = &caller();
-    if ADDRESS_BOOK.with(|book| book.borrow().is_controller_or_custodian(caller))
-        || &api::id() == caller
-    {
+    let allowed = ADDRESS_BOOK.with(|book| {
+        let book = book.borrow();
+        book.is_controller_or_custodian(caller) && !book.has_lapsed(caller, api::time())
+    });
+    if allowed || &api::id() == caller {
         Ok(())
     } else {
         Err("Only a controller or custodian can call this method.".to_string())
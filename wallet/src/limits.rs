@@ -0,0 +1,254 @@
+use candid::{CandidType, Principal};
+use ic_cdk::api;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const WEEK_NANOS: u64 = 7 * DAY_NANOS;
+
+/// A spending limit for a custodian. Every bound is optional; a missing bound is unlimited.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SpendingLimit {
+    /// The maximum number of cycles a single operation may spend.
+    pub per_transaction: Option<u128>,
+    /// The maximum number of cycles that may be spent over any rolling 24 hours.
+    pub daily: Option<u128>,
+    /// The maximum number of cycles that may be spent over any rolling 7 days.
+    pub weekly: Option<u128>,
+}
+
+/// The current state of a custodian's allowance, as reported to the wallet API.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SpendingLimitStatus {
+    pub limit: SpendingLimit,
+    pub spent_last_day: u128,
+    pub spent_last_week: u128,
+    /// The most a single operation can spend right now, or `None` if unlimited.
+    pub remaining: Option<u128>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct Spend {
+    id: u64,
+    timestamp: u64,
+    amount: u128,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct Allowance {
+    limit: SpendingLimit,
+    spends: VecDeque<Spend>,
+}
+
+impl Allowance {
+    fn prune(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(WEEK_NANOS);
+        while matches!(self.spends.front(), Some(spend) if spend.timestamp <= cutoff) {
+            self.spends.pop_front();
+        }
+    }
+
+    fn spent_since(&self, since: u64) -> u128 {
+        self.spends
+            .iter()
+            .filter(|spend| spend.timestamp > since)
+            .map(|spend| spend.amount)
+            .sum()
+    }
+
+    fn status(&self, now: u64) -> SpendingLimitStatus {
+        let spent_last_day = self.spent_since(now.saturating_sub(DAY_NANOS));
+        let spent_last_week = self.spent_since(now.saturating_sub(WEEK_NANOS));
+        let remaining = [
+            self.limit.per_transaction,
+            self.limit
+                .daily
+                .map(|daily| daily.saturating_sub(spent_last_day)),
+            self.limit
+                .weekly
+                .map(|weekly| weekly.saturating_sub(spent_last_week)),
+        ]
+        .into_iter()
+        .flatten()
+        .min();
+        SpendingLimitStatus {
+            limit: self.limit.clone(),
+            spent_last_day,
+            spent_last_week,
+            remaining,
+        }
+    }
+}
+
+/// A reservation against a custodian's allowance, made before cycles leave the wallet.
+///
+/// Must be passed to [`settle`] once the actual amount spent (after refunds) is known.
+#[must_use]
+pub struct Reservation {
+    custodian: Principal,
+    id: u64,
+}
+
+/// The spending limits of every custodian that has one.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct SpendingLimits {
+    allowances: BTreeMap<Principal, Allowance>,
+    next_id: u64,
+}
+
+thread_local! {
+    pub static SPENDING_LIMITS: RefCell<SpendingLimits> = Default::default();
+}
+
+impl SpendingLimits {
+    pub fn set(&mut self, custodian: Principal, limit: Option<SpendingLimit>) {
+        match limit {
+            Some(limit) => self.allowances.entry(custodian).or_default().limit = limit,
+            None => {
+                self.allowances.remove(&custodian);
+            }
+        }
+    }
+
+    pub fn status(&self, custodian: &Principal, now: u64) -> Option<SpendingLimitStatus> {
+        self.allowances
+            .get(custodian)
+            .map(|allowance| allowance.status(now))
+    }
+
+    /// Reserve `amount` cycles against the custodian's allowance, or explain why it can't be spent.
+    ///
+    /// Returns `None` if the custodian has no spending limit.
+    pub fn reserve(
+        &mut self,
+        custodian: Principal,
+        amount: u128,
+        now: u64,
+    ) -> Result<Option<Reservation>, String> {
+        let allowance = match self.allowances.get_mut(&custodian) {
+            Some(allowance) => allowance,
+            None => return Ok(None),
+        };
+        allowance.prune(now);
+        let status = allowance.status(now);
+        if let Some(per_transaction) = status.limit.per_transaction {
+            if amount > per_transaction {
+                return Err(format!(
+                    "Cannot spend {} cycles: the per-transaction limit is {} cycles.",
+                    amount, per_transaction
+                ));
+            }
+        }
+        if let Some(daily) = status.limit.daily {
+            if status.spent_last_day.saturating_add(amount) > daily {
+                return Err(format!(
+                    "Cannot spend {} cycles: {} of the daily limit of {} cycles have already been spent.",
+                    amount, status.spent_last_day, daily
+                ));
+            }
+        }
+        if let Some(weekly) = status.limit.weekly {
+            if status.spent_last_week.saturating_add(amount) > weekly {
+                return Err(format!(
+                    "Cannot spend {} cycles: {} of the weekly limit of {} cycles have already been spent.",
+                    amount, status.spent_last_week, weekly
+                ));
+            }
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        allowance.spends.push_back(Spend {
+            id,
+            timestamp: now,
+            amount,
+        });
+        Ok(Some(Reservation { custodian, id }))
+    }
+
+    /// Replace the reserved amount with the amount that was actually spent.
+    pub fn settle(&mut self, Reservation { custodian, id }: Reservation, spent: u128) {
+        if let Some(allowance) = self.allowances.get_mut(&custodian) {
+            if let Some(index) = allowance.spends.iter().position(|spend| spend.id == id) {
+                if spent == 0 {
+                    allowance.spends.remove(index);
+                } else {
+                    allowance.spends[index].amount = spent;
+                }
+            }
+        }
+    }
+}
+
+/// Reserve `amount` cycles against the allowance of `caller`.
+///
+/// Controllers and the wallet itself are never limited.
+pub fn reserve(caller: Principal, amount: u128) -> Result<Option<Reservation>, String> {
+    if caller == api::id() || crate::ADDRESS_BOOK.with(|book| book.borrow().is_controller(&caller))
+    {
+        return Ok(None);
+    }
+    SPENDING_LIMITS.with(|limits| limits.borrow_mut().reserve(caller, amount, api::time()))
+}
+
+/// Settle a reservation made by [`reserve`] with the amount that was actually spent.
+pub fn settle(reservation: Option<Reservation>, spent: u128) {
+    if let Some(reservation) = reservation {
+        SPENDING_LIMITS.with(|limits| limits.borrow_mut().settle(reservation, spent));
+    }
+}
+
+/// The most `caller` may spend in a single operation right now, or `None` if unlimited.
+pub fn remaining(caller: &Principal) -> Option<u128> {
+    if caller == &api::id() || crate::ADDRESS_BOOK.with(|book| book.borrow().is_controller(caller))
+    {
+        return None;
+    }
+    SPENDING_LIMITS.with(|limits| {
+        limits
+            .borrow()
+            .status(caller, api::time())
+            .and_then(|status| status.remaining)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SpendingLimit, SpendingLimits, DAY_NANOS};
+    use candid::Principal;
+
+    #[test]
+    fn enforces_rolling_windows() {
+        let custodian = Principal::anonymous();
+        let mut limits = SpendingLimits::default();
+        limits.set(
+            custodian,
+            Some(SpendingLimit {
+                per_transaction: Some(600),
+                daily: Some(1_000),
+                weekly: Some(1_500),
+            }),
+        );
+        let now = 10 * DAY_NANOS;
+        assert!(limits.reserve(custodian, 700, now).is_err());
+        let reservation = limits.reserve(custodian, 600, now).unwrap();
+        limits.settle(reservation.unwrap(), 500);
+        assert!(limits.reserve(custodian, 600, now + 1).is_err());
+        assert!(limits.reserve(custodian, 500, now + 1).unwrap().is_some());
+        assert_eq!(
+            limits.status(&custodian, now + 1).unwrap().remaining,
+            Some(0)
+        );
+        assert!(limits.reserve(custodian, 600, now + DAY_NANOS).is_err());
+        assert!(limits.reserve(custodian, 500, now + DAY_NANOS).is_ok());
+    }
+
+    #[test]
+    fn unlimited_without_a_limit() {
+        let mut limits = SpendingLimits::default();
+        assert!(limits
+            .reserve(Principal::anonymous(), u128::MAX, 0)
+            .unwrap()
+            .is_none());
+    }
+}
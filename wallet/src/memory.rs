@@ -0,0 +1,110 @@
+use candid::{CandidType, Decode, Encode};
+use ic_cdk::api::stable;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::reader::Reader;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::writer::Writer;
+use ic_stable_structures::{DefaultMemoryImpl, Storable};
+use serde::de::DeserializeOwned;
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// A region of stable memory handed out by the memory manager.
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+/// The state that is small enough to be serialized in `#[pre_upgrade]`.
+pub const UPGRADES: MemoryId = MemoryId::new(0);
+pub const EVENTS: MemoryId = MemoryId::new(1);
+pub const ADDRESS_BOOK: MemoryId = MemoryId::new(2);
+/// The managed canisters, in the order they were first seen.
+pub const MANAGED_CANISTER_IDS: MemoryId = MemoryId::new(3);
+pub const MANAGED_CANISTER_INFO: MemoryId = MemoryId::new(4);
+pub const MANAGED_CANISTER_EVENTS: MemoryId = MemoryId::new(5);
+// Memory 6 is unused.
+pub const WALLET_WASM: MemoryId = MemoryId::new(7);
+pub const CHART_MINUTES: MemoryId = MemoryId::new(8);
+pub const CHART_HOURS: MemoryId = MemoryId::new(9);
+pub const CHART_DAYS: MemoryId = MemoryId::new(10);
+/// The modules of open `StoreWalletWasm` proposals, by proposal ID.
+pub const PROPOSAL_WASM_MODULES: MemoryId = MemoryId::new(11);
+
+/// The magic bytes the memory manager writes at the start of stable memory.
+const MEMORY_MANAGER_MAGIC: &[u8; 3] = b"MGR";
+
+thread_local! {
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+pub fn get(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with(|manager| manager.borrow().get(id))
+}
+
+/// Whether stable memory holds the candid blob of a wallet from before stable structures were used.
+///
+/// Must be called before anything touches the memory manager, which overwrites the start of stable memory.
+pub fn is_legacy_layout() -> bool {
+    if stable::stable64_size() == 0 {
+        return false;
+    }
+    let mut magic = [0; 3];
+    stable::stable64_read(0, &mut magic);
+    &magic != MEMORY_MANAGER_MAGIC
+}
+
+/// Replace the contents of `memory` with `bytes`, prefixed by their length.
+pub fn write_blob(memory: &mut Memory, bytes: &[u8]) -> Result<(), String> {
+    let mut writer = Writer::new(memory, 0);
+    writer
+        .write(&(bytes.len() as u64).to_le_bytes())
+        .and_then(|()| writer.write(bytes))
+        .map_err(|err| format!("Could not grow stable memory: {:?}", err))
+}
+
+/// Read the bytes written by [`write_blob`]. Returns `None` if nothing was ever written.
+pub fn read_blob(memory: &Memory) -> Option<Vec<u8>> {
+    let mut reader = Reader::new(memory, 0);
+    let mut len = [0; 8];
+    reader
+        .read(&mut len)
+        .ok()
+        .filter(|&read| read == len.len())?;
+    let mut bytes = vec![0; u64::from_le_bytes(len) as usize];
+    reader.read(&mut bytes).ok()?;
+    Some(bytes)
+}
+
+/// Serialize `value` with candid for storage in a stable structure.
+pub fn encode<T: CandidType>(value: &T) -> Cow<[u8]> {
+    Cow::Owned(Encode!(value).expect("Could not serialize to stable memory."))
+}
+
+/// Deserialize a value written by [`encode`].
+pub fn decode<T: CandidType + DeserializeOwned>(bytes: &[u8]) -> T {
+    Decode!(bytes, T).expect("Could not deserialize from stable memory.")
+}
+
+/// Implement [`Storable`] for types that are stored candid-encoded.
+macro_rules! candid_storable {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl Storable for $ty {
+                fn to_bytes(&self) -> Cow<[u8]> {
+                    encode(self)
+                }
+
+                fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                    decode(&bytes)
+                }
+
+                const BOUND: Bound = Bound::Unbounded;
+            }
+        )*
+    };
+}
+
+candid_storable!(
+    crate::address::AddressEntry,
+    crate::events::ManagedCanisterInfo,
+    crate::events::ManagedCanisterEvent,
+);
@@ -3,18 +3,32 @@ use crate::*;
 use ic_cdk::storage;
 
 pub mod v1;
+pub mod v2;
+pub mod v3;
 use v1::*;
+use v2::*;
+use v3::*;
 
-use Event as V2Event;
-use EventBuffer as V2EventBuffer;
 use EventKind as V2EventKind;
-use ManagedCanister as V2ManagedCanister;
 use ManagedCanisterEvent as V2ManagedCanisterEvent;
 use ManagedCanisterEventKind as V2ManagedCanisterEventKind;
-use ManagedList as V2ManagedList;
-use StableStorage as V2StableStorage;
 
-pub(crate) fn migrate_from(version: u32) -> Option<StableStorage> {
+/// Restores the state saved as a single candid blob by wallets from before stable structures were used.
+///
+/// Call during `#[post_upgrade]`, before anything touches the memory manager, which overwrites the blob.
+pub(crate) fn migrate_legacy() -> Option<StableStorage> {
+    let v3 = if let Ok((storage, Some(3))) =
+        storage::stable_restore::<(V3StableStorage, Option<u32>)>()
+    {
+        storage
+    } else {
+        let (_, version) = storage::stable_restore::<(Reserved, Option<u32>)>().ok()?;
+        migrate_from(version.unwrap_or(1))?
+    };
+    Some(_4_move_to_stable_structures(v3))
+}
+
+fn migrate_from(version: u32) -> Option<V3StableStorage> {
     let v2 = if version != 2 {
         let (mut v1,) = storage::stable_restore::<(V1StableStorage,)>().ok()?;
         // from before versioning
@@ -25,7 +39,7 @@ pub(crate) fn migrate_from(version: u32) -> Option<StableStorage> {
     } else {
         storage::stable_restore::<(V2StableStorage,)>().ok()?.0
     };
-    Some(v2)
+    Some(_3_add_event_callers(v2))
 }
 
 /// Creates the managed canister list from the event list.
@@ -74,6 +88,7 @@ pub(crate) fn _2_convert_nat64_to_nat(
                         canister,
                         cycles: cycles as u128,
                         method_name,
+                        refund: None,
                     },
                     V1EventKind::CanisterCreated { canister, cycles } => {
                         V2EventKind::CanisterCreated {
@@ -86,12 +101,15 @@ pub(crate) fn _2_convert_nat64_to_nat(
                             amount: amount as u128,
                             from,
                             memo,
+                            source: None,
                         }
                     }
                     V1EventKind::CyclesSent { amount, refund, to } => V2EventKind::CyclesSent {
                         amount: amount as u128,
                         refund: refund as u128,
                         to,
+                        schedule: None,
+                        memo: None,
                     },
                     V1EventKind::WalletDeployed { canister } => {
                         V2EventKind::WalletDeployed { canister }
@@ -126,6 +144,7 @@ pub(crate) fn _2_convert_nat64_to_nat(
                             } => V2ManagedCanisterEventKind::Called {
                                 cycles: cycles as u128,
                                 method_name,
+                                refund: None,
                             },
                             V1ManagedCanisterEventKind::Created { cycles } => {
                                 V2ManagedCanisterEventKind::Created {
@@ -158,5 +177,117 @@ pub(crate) fn _2_convert_nat64_to_nat(
         chart,
         wasm_module,
         managed,
+        spending_limits: None,
+        proposals: None,
+    }
+}
+
+/// Events recorded before callers were tracked have no caller or method.
+pub(crate) fn _3_add_event_callers(
+    V2StableStorage {
+        address_book,
+        events,
+        name,
+        chart,
+        wasm_module,
+        managed,
+        spending_limits,
+        proposals,
+    }: V2StableStorage,
+) -> V3StableStorage {
+    let events = events
+        .events
+        .into_iter()
+        .map(
+            |V2Event {
+                 id,
+                 timestamp,
+                 kind,
+             }| Event {
+                id,
+                timestamp,
+                kind,
+                caller: None,
+                method: None,
+                prev_hash: None,
+            },
+        )
+        .collect();
+    V3StableStorage {
+        address_book,
+        events: V3EventBuffer { events },
+        name,
+        chart,
+        wasm_module,
+        managed,
+        spending_limits,
+        proposals,
+    }
+}
+
+/// Moves the event log, address book, managed canisters, chart and wallet wasm into stable structures.
+///
+/// Returns the rest of the state, which is still saved as a candid blob on upgrade.
+pub(crate) fn _4_move_to_stable_structures(
+    V3StableStorage {
+        address_book,
+        events,
+        name,
+        chart,
+        wasm_module,
+        managed,
+        spending_limits,
+        proposals,
+    }: V3StableStorage,
+) -> StableStorage {
+    ADDRESS_BOOK.with(|book| {
+        let mut book = book.borrow_mut();
+        for entry in address_book {
+            book.insert(entry);
+        }
+    });
+    EVENT_BUFFER.with(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        for event in events.events {
+            buffer.push(event);
+        }
+    });
+    MANAGED_LIST.with(|list| {
+        let mut list = list.borrow_mut();
+        for (canister, V2ManagedCanister { info, events }) in managed.unwrap_or_default().0 {
+            list.insert_info(info);
+            // Event IDs used to repeat once a canister reached its event limit; keep them unique.
+            let mut next_id = 0;
+            for mut event in events {
+                event.id = event.id.max(next_id);
+                next_id = event.id + 1;
+                list.insert_event(canister, event);
+            }
+        }
+    });
+    // Samples older than five years before the last one would be dropped from the chart anyway.
+    let since = chart
+        .last()
+        .map_or(0, |last| crate::chart::kept_since(last.timestamp));
+    for tick in chart.into_iter().filter(|tick| tick.timestamp >= since) {
+        crate::chart::record(tick.timestamp, tick.cycles as u128);
+    }
+    WALLET_WASM_BYTES.with(|bytes| {
+        bytes
+            .borrow_mut()
+            .set(wasm_module.as_ref().map(|bytes| bytes.as_slice()))
+    });
+    StableStorage {
+        name,
+        spending_limits,
+        proposals,
+        top_ups: None,
+        cycles_ledger: None,
+        icp_canisters: None,
+        schedules: None,
+        batch_send_reserve: None,
+        access_requests: None,
+        alerts: None,
+        subscriptions: None,
     }
 }
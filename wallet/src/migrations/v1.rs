@@ -1,5 +1,6 @@
 use std::fmt::{self, Formatter};
 
+use crate::chart::ChartTick;
 use crate::events::*;
 use crate::*;
 use candid::types::{Compound, Serializer, Type, TypeInner};
@@ -89,7 +90,9 @@ pub struct V1ManagedList(pub IndexMap<Principal, V1ManagedCanister>);
 
 impl CandidType for V1ManagedList {
     fn _ty() -> Type {
-        Type(<_>::from(TypeInner::Vec(ManagedCanister::ty())))
+        Type(<_>::from(
+            TypeInner::Vec(super::v2::V2ManagedCanister::ty()),
+        ))
     }
     fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
     where
@@ -187,6 +190,8 @@ impl V1ManagedCanister {
                 id,
                 name: None,
                 created_at: api::time(),
+                deleted_at: None,
+                controlled: None,
             },
             events: vec![],
         }
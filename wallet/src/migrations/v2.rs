@@ -0,0 +1,87 @@
+use crate::chart::ChartTick;
+use crate::events::*;
+use crate::*;
+use candid::types::{Compound, Serializer, Type, TypeInner};
+use candid::{CandidType, Deserialize};
+use indexmap::IndexMap;
+use serde::de::{SeqAccess, Visitor};
+use serde::Deserializer;
+use std::collections::VecDeque;
+use std::fmt::{self, Formatter};
+
+#[derive(CandidType, Clone, Deserialize)]
+pub struct V2Event {
+    pub id: u32,
+    pub timestamp: u64,
+    pub kind: EventKind,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct V2EventBuffer {
+    pub events: VecDeque<V2Event>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct V2StableStorage {
+    pub address_book: Vec<AddressEntry>,
+    pub events: V2EventBuffer,
+    pub name: Option<String>,
+    pub chart: Vec<ChartTick>,
+    pub wasm_module: Option<serde_bytes::ByteBuf>,
+    pub managed: Option<V2ManagedList>,
+    pub spending_limits: Option<SpendingLimits>,
+    pub proposals: Option<Proposals>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct V2ManagedCanister {
+    pub info: ManagedCanisterInfo,
+    pub events: VecDeque<ManagedCanisterEvent>,
+}
+
+#[derive(Default)]
+pub struct V2ManagedList(pub IndexMap<Principal, V2ManagedCanister>);
+
+impl CandidType for V2ManagedList {
+    fn _ty() -> Type {
+        Type(<_>::from(TypeInner::Vec(V2ManagedCanister::ty())))
+    }
+    fn idl_serialize<S>(&self, serializer: S) -> Result<(), S::Error>
+    where
+        S: Serializer,
+    {
+        let mut compound = serializer.serialize_vec(self.0.len())?;
+        for value in self.0.values() {
+            compound.serialize_element(value)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for V2ManagedList {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_seq(IdxMapVisitor)
+    }
+}
+
+struct IdxMapVisitor;
+
+impl<'de> Visitor<'de> for IdxMapVisitor {
+    type Value = V2ManagedList;
+    fn expecting(&self, formatter: &mut Formatter) -> fmt::Result {
+        write!(formatter, "a sequence of `ManagedList` records")
+    }
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut map = IndexMap::with_capacity(seq.size_hint().unwrap_or(20));
+        while let Some(elem) = seq.next_element::<V2ManagedCanister>()? {
+            map.insert(elem.info.id, elem);
+        }
+        Ok(V2ManagedList(map))
+    }
+}
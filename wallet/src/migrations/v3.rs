@@ -0,0 +1,24 @@
+use crate::chart::ChartTick;
+use crate::events::*;
+use crate::migrations::v2::V2ManagedList;
+use crate::*;
+use candid::{CandidType, Deserialize};
+use std::collections::VecDeque;
+
+#[derive(CandidType, Deserialize)]
+pub struct V3EventBuffer {
+    pub events: VecDeque<Event>,
+}
+
+/// The last version of the wallet state to be saved as a single candid blob.
+#[derive(CandidType, Deserialize)]
+pub struct V3StableStorage {
+    pub address_book: Vec<AddressEntry>,
+    pub events: V3EventBuffer,
+    pub name: Option<String>,
+    pub chart: Vec<ChartTick>,
+    pub wasm_module: Option<serde_bytes::ByteBuf>,
+    pub managed: Option<V2ManagedList>,
+    pub spending_limits: Option<SpendingLimits>,
+    pub proposals: Option<Proposals>,
+}
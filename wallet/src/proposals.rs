@@ -0,0 +1,311 @@
+use crate::memory::{self, Memory};
+use candid::{CandidType, Principal};
+use ic_stable_structures::StableBTreeMap;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+/// The number of closed proposals kept around for reference.
+const MAX_CLOSED_PROPOSALS: usize = 100;
+/// The number of proposals that can be open at the same time.
+const MAX_OPEN_PROPOSALS: usize = 50;
+
+/// How many controllers need to approve high-value operations.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct MultisigPolicy {
+    /// The number of controller approvals an operation needs. A threshold of 1 disables proposals.
+    pub threshold: u32,
+    /// Cycle sends and forwarded calls above this amount need approval. If `None`, they never do.
+    pub high_value_cycles: Option<u128>,
+    /// How long a proposal stays open, in nanoseconds.
+    pub proposal_ttl: u64,
+}
+
+impl Default for MultisigPolicy {
+    fn default() -> Self {
+        Self {
+            threshold: 1,
+            high_value_cycles: None,
+            // A week.
+            proposal_ttl: 7 * 24 * 60 * 60 * 1_000_000_000,
+        }
+    }
+}
+
+/// An operation that needs the approval of several controllers.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum Operation {
+    SendCycles {
+        canister: Principal,
+        amount: u128,
+    },
+    CallCanister {
+        canister: Principal,
+        method_name: String,
+        args: ByteBuf,
+        cycles: u128,
+    },
+    AddController {
+        controller: Principal,
+    },
+    RemoveController {
+        controller: Principal,
+    },
+    /// The module itself is passed alongside the proposal; approvers can check it against this hash.
+    StoreWalletWasm {
+        wasm_module_hash: ByteBuf,
+    },
+    SetPolicy {
+        policy: MultisigPolicy,
+    },
+}
+
+impl Operation {
+    /// Whether custodians (and not only controllers) may propose this operation.
+    pub fn custodian_may_propose(&self) -> bool {
+        matches!(self, Self::SendCycles { .. } | Self::CallCanister { .. })
+    }
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub enum ProposalStatus {
+    Open,
+    /// Enough controllers approved and the operation is being executed. If the execution traps, the
+    /// proposal fails.
+    Executing,
+    Executed {
+        reply: Option<ByteBuf>,
+    },
+    Failed {
+        error: String,
+    },
+    Rejected,
+    Expired,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Proposal {
+    pub id: u64,
+    pub proposer: Principal,
+    pub operation: Operation,
+    pub created_at: u64,
+    pub expires_at: u64,
+    pub approvals: Vec<Principal>,
+    pub rejections: Vec<Principal>,
+    pub status: ProposalStatus,
+}
+
+impl Proposal {
+    pub fn is_open(&self) -> bool {
+        matches!(self.status, ProposalStatus::Open)
+    }
+}
+
+/// All the proposals of this wallet, along with the policy that governs them.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Proposals {
+    pub policy: MultisigPolicy,
+    proposals: VecDeque<Proposal>,
+    next_id: u64,
+}
+
+thread_local! {
+    pub static PROPOSALS: RefCell<Proposals> = Default::default();
+    /// The modules of open `StoreWalletWasm` proposals, by proposal ID. They are kept in stable
+    /// memory so they don't have to be saved on upgrade.
+    static WASM_MODULES: RefCell<StableBTreeMap<u64, Vec<u8>, Memory>> =
+        RefCell::new(StableBTreeMap::init(memory::get(memory::PROPOSAL_WASM_MODULES)));
+}
+
+impl Proposals {
+    /// The number of approvals needed when the wallet has `controllers` controllers.
+    pub fn required_approvals(&self, controllers: usize) -> usize {
+        (self.policy.threshold as usize).clamp(1, controllers.max(1))
+    }
+
+    pub fn submit(
+        &mut self,
+        proposer: Principal,
+        operation: Operation,
+        wasm_module: Option<ByteBuf>,
+        now: u64,
+    ) -> Result<u64, String> {
+        if self.proposals.iter().filter(|p| p.is_open()).count() >= MAX_OPEN_PROPOSALS {
+            return Err(format!(
+                "There cannot be more than {} open proposals.",
+                MAX_OPEN_PROPOSALS
+            ));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        if let Some(wasm_module) = wasm_module {
+            WASM_MODULES.with(|modules| modules.borrow_mut().insert(id, wasm_module.into_vec()));
+        }
+        self.proposals.push_back(Proposal {
+            id,
+            proposer,
+            operation,
+            created_at: now,
+            expires_at: now.saturating_add(self.policy.proposal_ttl),
+            approvals: vec![],
+            rejections: vec![],
+            status: ProposalStatus::Open,
+        });
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Proposal> {
+        self.proposals.iter().find(|p| p.id == id)
+    }
+
+    pub fn get_open_mut(&mut self, id: u64) -> Result<&mut Proposal, String> {
+        let proposal = self
+            .proposals
+            .iter_mut()
+            .find(|p| p.id == id)
+            .ok_or_else(|| format!("Proposal {} does not exist.", id))?;
+        if proposal.is_open() {
+            Ok(proposal)
+        } else {
+            Err(format!("Proposal {} is no longer open.", id))
+        }
+    }
+
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Proposal> {
+        self.proposals.iter()
+    }
+
+    /// Remove and return the module of a `StoreWalletWasm` proposal.
+    pub fn take_wasm_module(&mut self, id: u64) -> Option<ByteBuf> {
+        WASM_MODULES
+            .with(|modules| modules.borrow_mut().remove(&id))
+            .map(ByteBuf::from)
+    }
+
+    /// Mark open proposals that have outlived their TTL as expired, returning their IDs.
+    pub fn expire(&mut self, now: u64) -> Vec<u64> {
+        let mut expired = vec![];
+        for proposal in self.proposals.iter_mut() {
+            if proposal.is_open() && proposal.expires_at <= now {
+                proposal.status = ProposalStatus::Expired;
+                expired.push(proposal.id);
+            }
+        }
+        self.prune();
+        expired
+    }
+
+    /// Set the final status of a proposal and forget the data it no longer needs.
+    pub fn close(&mut self, id: u64, status: ProposalStatus) {
+        if let Some(proposal) = self.proposals.iter_mut().find(|p| p.id == id) {
+            proposal.status = status;
+        }
+        self.prune();
+    }
+
+    fn prune(&mut self) {
+        let open: Vec<u64> = self
+            .proposals
+            .iter()
+            .filter(|p| p.is_open() || matches!(p.status, ProposalStatus::Executing))
+            .map(|p| p.id)
+            .collect();
+        WASM_MODULES.with(|modules| {
+            let mut modules = modules.borrow_mut();
+            let closed: Vec<u64> = modules
+                .iter()
+                .map(|(id, _)| id)
+                .filter(|id| !open.contains(id))
+                .collect();
+            for id in closed {
+                modules.remove(&id);
+            }
+        });
+        let mut closed = self.proposals.len() - open.len();
+        self.proposals.retain(|p| {
+            if closed > MAX_CLOSED_PROPOSALS && !open.contains(&p.id) {
+                closed -= 1;
+                false
+            } else {
+                true
+            }
+        });
+    }
+}
+
+/// Fail if the multi-signature policy requires `action` to go through a proposal.
+pub fn check_direct(action: &str) -> Result<(), String> {
+    PROPOSALS.with(|proposals| {
+        let threshold = proposals.borrow().policy.threshold;
+        if threshold > 1 {
+            Err(format!(
+                "Cannot {} directly: it needs the approval of {} controllers. Use `submit_proposal` instead.",
+                action, threshold
+            ))
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Fail if spending `cycles` needs the approval of several controllers.
+pub fn check_direct_cycles(cycles: u128) -> Result<(), String> {
+    PROPOSALS.with(|proposals| {
+        let policy = &proposals.borrow().policy;
+        match policy.high_value_cycles {
+            Some(high_value) if policy.threshold > 1 && cycles > high_value => Err(format!(
+                "Spending {} cycles needs the approval of {} controllers. Use `submit_proposal` instead.",
+                cycles, policy.threshold
+            )),
+            _ => Ok(()),
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Operation, ProposalStatus, Proposals, MAX_CLOSED_PROPOSALS};
+    use candid::Principal;
+    use serde_bytes::ByteBuf;
+
+    #[test]
+    fn expires_and_prunes_proposals() {
+        let mut proposals = Proposals::default();
+        let operation = Operation::AddController {
+            controller: Principal::anonymous(),
+        };
+        for _ in 0..MAX_CLOSED_PROPOSALS + 10 {
+            let id = proposals
+                .submit(Principal::anonymous(), operation.clone(), None, 0)
+                .unwrap();
+            proposals.close(id, ProposalStatus::Rejected);
+        }
+        let open = proposals
+            .submit(Principal::anonymous(), operation, None, 0)
+            .unwrap();
+        assert_eq!(proposals.iter().count(), MAX_CLOSED_PROPOSALS + 1);
+        assert!(proposals.expire(1).is_empty());
+        let ttl = proposals.policy.proposal_ttl;
+        assert_eq!(proposals.expire(ttl), vec![open]);
+        assert!(proposals.get_open_mut(open).is_err());
+    }
+
+    #[test]
+    fn keeps_wasm_modules_of_open_proposals() {
+        let mut proposals = Proposals::default();
+        let operation = Operation::StoreWalletWasm {
+            wasm_module_hash: ByteBuf::new(),
+        };
+        let module = || Some(ByteBuf::from(vec![0, 0x61, 0x73, 0x6d]));
+        let rejected = proposals
+            .submit(Principal::anonymous(), operation.clone(), module(), 0)
+            .unwrap();
+        let open = proposals
+            .submit(Principal::anonymous(), operation, module(), 0)
+            .unwrap();
+        proposals.close(rejected, ProposalStatus::Rejected);
+        assert_eq!(proposals.take_wasm_module(rejected), None);
+        assert_eq!(proposals.take_wasm_module(open), module());
+    }
+}
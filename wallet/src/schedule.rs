@@ -0,0 +1,386 @@
+use crate::events::{self, EventKind};
+use candid::{CandidType, Principal};
+use ic_cdk::api;
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How often the timer looks for payments that are due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// The shortest interval between two payments of a recurring schedule, in nanoseconds.
+const MIN_INTERVAL: u64 = 60 * 1_000_000_000;
+/// The name events recorded by the payment timer are attributed to.
+const TIMER_METHOD: &str = "timer:scheduled_payment";
+
+/// A payment to make once, or repeatedly, in the future.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct PaymentSchedule {
+    pub canister: Principal,
+    pub amount: u128,
+    /// When the first payment is due, in nanoseconds since the epoch.
+    pub start_at: u64,
+    /// The nanoseconds between two payments. `None` for a one-off payment.
+    pub interval: Option<u64>,
+    /// No payment is made after this time.
+    pub end_at: Option<u64>,
+    /// The most payments to make.
+    pub max_occurrences: Option<u32>,
+}
+
+/// A payment schedule and how far along it is.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ScheduledPayment {
+    pub id: u64,
+    pub schedule: PaymentSchedule,
+    /// When the next payment is due.
+    pub next_run: u64,
+    /// The number of payments made successfully so far.
+    pub occurrences: u32,
+    pub paused: bool,
+    /// The error of the last payment, if it failed. A one-off payment that failed is paused, to be
+    /// retried by resuming it.
+    pub last_error: Option<String>,
+}
+
+/// A payment that is due in the future.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct UpcomingPayment {
+    pub id: u64,
+    pub canister: Principal,
+    pub amount: u128,
+    pub at: u64,
+}
+
+impl ScheduledPayment {
+    fn is_due(&self, now: u64) -> bool {
+        !self.paused && self.next_run <= now
+    }
+
+    /// Whether a payment due at `at` is still part of the schedule, after `occurrences` payments.
+    fn allows(&self, at: u64, occurrences: u32) -> bool {
+        self.schedule.end_at.map_or(true, |end_at| at <= end_at)
+            && self
+                .schedule
+                .max_occurrences
+                .map_or(true, |max| occurrences < max)
+    }
+
+    /// When the payment after the one due at `at` is due, skipping the ones missed before `now`.
+    fn next_after(&self, at: u64, now: u64) -> Option<u64> {
+        let interval = self.schedule.interval?;
+        let missed = now.saturating_sub(at) / interval;
+        at.checked_add(interval.checked_mul(missed + 1)?)
+    }
+}
+
+/// The payment schedules of the wallet.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Schedules {
+    next_id: u64,
+    payments: BTreeMap<u64, ScheduledPayment>,
+}
+
+thread_local! {
+    pub static SCHEDULES: RefCell<Schedules> = Default::default();
+    /// Whether the timer is still making payments, so runs don't overlap.
+    static RUNNING: Cell<bool> = Cell::new(false);
+}
+
+impl Schedules {
+    pub fn add(&mut self, schedule: PaymentSchedule) -> Result<u64, String> {
+        validate(&schedule)?;
+        let id = self.next_id;
+        self.next_id += 1;
+        self.payments.insert(
+            id,
+            ScheduledPayment {
+                id,
+                next_run: schedule.start_at,
+                schedule,
+                occurrences: 0,
+                paused: false,
+                last_error: None,
+            },
+        );
+        Ok(id)
+    }
+
+    pub fn set_paused(&mut self, id: u64, paused: bool) -> Result<(), String> {
+        let payment = self
+            .payments
+            .get_mut(&id)
+            .ok_or_else(|| format!("No scheduled payment with ID {}.", id))?;
+        payment.paused = paused;
+        Ok(())
+    }
+
+    pub fn cancel(&mut self, id: u64) -> Result<(), String> {
+        self.payments
+            .remove(&id)
+            .map(|_| ())
+            .ok_or_else(|| format!("No scheduled payment with ID {}.", id))
+    }
+
+    pub fn list(&self) -> Vec<ScheduledPayment> {
+        self.payments.values().cloned().collect()
+    }
+
+    /// The payments that are due at `now`, as `(id, canister, amount)`.
+    fn due(&self, now: u64) -> Vec<(u64, Principal, u128)> {
+        self.payments
+            .values()
+            .filter(|payment| payment.is_due(now))
+            .map(|payment| {
+                (
+                    payment.id,
+                    payment.schedule.canister,
+                    payment.schedule.amount,
+                )
+            })
+            .collect()
+    }
+
+    /// The destination and amount of payment `id`, if it is still due at `now`.
+    fn still_due(&self, id: u64, now: u64) -> Option<(Principal, u128)> {
+        self.payments
+            .get(&id)
+            .filter(|payment| payment.is_due(now))
+            .map(|payment| (payment.schedule.canister, payment.schedule.amount))
+    }
+
+    /// Move a schedule past the payment that was just made, removing it once it is over.
+    ///
+    /// Failed payments don't count towards `max_occurrences`, and a failed one-off payment is
+    /// paused rather than removed.
+    fn complete(&mut self, id: u64, now: u64, error: Option<String>) {
+        if let Some(payment) = self.payments.get_mut(&id) {
+            let failed = error.is_some();
+            if !failed {
+                payment.occurrences += 1;
+            }
+            payment.last_error = error;
+            if failed && payment.schedule.interval.is_none() {
+                payment.paused = true;
+                return;
+            }
+            match payment.next_after(payment.next_run, now) {
+                Some(next_run) if payment.allows(next_run, payment.occurrences) => {
+                    payment.next_run = next_run;
+                }
+                _ => {
+                    self.payments.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// The payments due until `until`, soonest first, at most `limit` of them.
+    pub fn upcoming(&self, until: u64, limit: usize) -> Vec<UpcomingPayment> {
+        let mut upcoming = Vec::new();
+        for payment in self.payments.values().filter(|payment| !payment.paused) {
+            let mut at = Some(payment.next_run);
+            let mut occurrences = payment.occurrences;
+            // Later runs of this schedule cannot be among the `limit` soonest.
+            for _ in 0..limit {
+                match at {
+                    Some(time) if time <= until && payment.allows(time, occurrences) => {
+                        upcoming.push(UpcomingPayment {
+                            id: payment.id,
+                            canister: payment.schedule.canister,
+                            amount: payment.schedule.amount,
+                            at: time,
+                        });
+                        occurrences = occurrences.saturating_add(1);
+                        at = payment.next_after(time, time);
+                    }
+                    _ => break,
+                }
+            }
+        }
+        upcoming.sort_by_key(|payment| payment.at);
+        upcoming.truncate(limit);
+        upcoming
+    }
+}
+
+/// Check that a schedule makes sense.
+fn validate(schedule: &PaymentSchedule) -> Result<(), String> {
+    if schedule.amount == 0 {
+        return Err("Cannot schedule a payment of 0 cycles.".to_string());
+    }
+    if matches!(schedule.interval, Some(interval) if interval < MIN_INTERVAL) {
+        return Err(format!(
+            "Payments cannot recur more often than every {} seconds.",
+            MIN_INTERVAL / 1_000_000_000
+        ));
+    }
+    if matches!(schedule.end_at, Some(end_at) if end_at < schedule.start_at) {
+        return Err("A schedule cannot end before it starts.".to_string());
+    }
+    if schedule.max_occurrences == Some(0) {
+        return Err("A schedule must allow at least one payment.".to_string());
+    }
+    Ok(())
+}
+
+/// Start making the scheduled payments as they fall due.
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || ic_cdk::spawn(pay_due()));
+}
+
+/// Clears [`RUNNING`] when a run ends, even if one of its calls trapped.
+struct RunGuard;
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(false));
+    }
+}
+
+async fn pay_due() {
+    if RUNNING.with(|running| running.replace(true)) {
+        return;
+    }
+    let _guard = RunGuard;
+    let due = SCHEDULES.with(|schedules| schedules.borrow().due(api::time()));
+    for (id, ..) in due {
+        // The schedule may have been paused, cancelled or changed while earlier payments were made.
+        let Some((canister, amount)) =
+            SCHEDULES.with(|schedules| schedules.borrow().still_due(id, api::time()))
+        else {
+            continue;
+        };
+        let result = pay(id, canister, amount).await;
+        SCHEDULES.with(|schedules| {
+            schedules
+                .borrow_mut()
+                .complete(id, api::time(), result.err())
+        });
+    }
+}
+
+#[derive(CandidType)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+async fn pay(id: u64, canister: Principal, amount: u128) -> Result<(), String> {
+    let result: Result<(), _> = api::call::call_with_payment128(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (CanisterIdRecord {
+            canister_id: canister,
+        },),
+        amount,
+    )
+    .await;
+    events::record_from(
+        api::id(),
+        TIMER_METHOD,
+        EventKind::CyclesSent {
+            to: canister,
+            amount,
+            refund: api::call::msg_cycles_refunded128(),
+            schedule: Some(id),
+            memo: None,
+        },
+    );
+    crate::update_chart();
+    result.map_err(|(code, msg)| {
+        format!("An error happened during the call: {}: {}", code as u8, msg)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PaymentSchedule, Schedules, UpcomingPayment};
+    use candid::Principal;
+
+    const WEEK: u64 = 7 * 24 * 60 * 60 * 1_000_000_000;
+
+    #[test]
+    fn runs_recurring_payments() {
+        let canister = Principal::anonymous();
+        let mut schedules = Schedules::default();
+        let id = schedules
+            .add(PaymentSchedule {
+                canister,
+                amount: 100,
+                start_at: WEEK,
+                interval: Some(WEEK),
+                end_at: None,
+                max_occurrences: Some(3),
+            })
+            .unwrap();
+        let upcoming = |at| UpcomingPayment {
+            id,
+            canister,
+            amount: 100,
+            at,
+        };
+        assert_eq!(
+            schedules.upcoming(u64::MAX, 10),
+            vec![upcoming(WEEK), upcoming(2 * WEEK), upcoming(3 * WEEK)]
+        );
+        assert!(schedules.due(WEEK - 1).is_empty());
+        assert_eq!(schedules.due(WEEK), vec![(id, canister, 100)]);
+
+        // Missed payments are skipped rather than made late, and don't count.
+        schedules.complete(id, 2 * WEEK + 1, None);
+        assert_eq!(
+            schedules.upcoming(u64::MAX, 10),
+            vec![upcoming(3 * WEEK), upcoming(4 * WEEK)]
+        );
+        assert_eq!(schedules.upcoming(u64::MAX, 1), vec![upcoming(3 * WEEK)]);
+
+        schedules.set_paused(id, true).unwrap();
+        assert!(schedules.due(3 * WEEK).is_empty());
+        schedules.set_paused(id, false).unwrap();
+        schedules.complete(id, 3 * WEEK, None);
+        schedules.complete(id, 4 * WEEK, None);
+        assert!(schedules.list().is_empty());
+    }
+
+    #[test]
+    fn keeps_failed_payments() {
+        let canister = Principal::anonymous();
+        let mut schedules = Schedules::default();
+        let recurring = schedules
+            .add(PaymentSchedule {
+                canister,
+                amount: 100,
+                start_at: WEEK,
+                interval: Some(WEEK),
+                end_at: None,
+                max_occurrences: Some(1),
+            })
+            .unwrap();
+        let once = schedules
+            .add(PaymentSchedule {
+                canister,
+                amount: 200,
+                start_at: WEEK,
+                interval: None,
+                end_at: None,
+                max_occurrences: None,
+            })
+            .unwrap();
+
+        // A failed payment doesn't count, and a failed one-off waits to be retried.
+        schedules.complete(recurring, WEEK, Some("out of cycles".to_string()));
+        schedules.complete(once, WEEK, Some("out of cycles".to_string()));
+        let list = schedules.list();
+        assert_eq!(list[0].occurrences, 0);
+        assert_eq!(list[0].next_run, 2 * WEEK);
+        assert_eq!(list[1].last_error.as_deref(), Some("out of cycles"));
+        assert!(list[1].paused);
+        assert_eq!(schedules.still_due(once, 2 * WEEK), None);
+
+        schedules.set_paused(once, false).unwrap();
+        assert_eq!(schedules.still_due(once, 2 * WEEK), Some((canister, 200)));
+        schedules.complete(once, 2 * WEEK, None);
+        schedules.complete(recurring, 2 * WEEK, None);
+        assert!(schedules.list().is_empty());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::address::ADDRESS_BOOK;
+use crate::events::{Event, EventKind, EVENT_BUFFER, MAX_SCANNED_EVENTS};
+use candid::{CandidType, Principal};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::ops::Range;
+
+/// The most groups kept in each breakdown of a report.
+const MAX_GROUPS: usize = 20;
+
+/// The cycles spent on one kind of thing, and how many times.
+#[derive(Clone, Debug, Default, PartialEq, Eq, CandidType, Deserialize)]
+pub struct SpendingTotal {
+    pub cycles: u128,
+    pub count: u32,
+}
+
+impl SpendingTotal {
+    fn add(&mut self, cycles: u128) {
+        self.cycles = self.cycles.saturating_add(cycles);
+        self.count = self.count.saturating_add(1);
+    }
+}
+
+/// The cycles the wallet spent over a period, grouped in several ways.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct SpendingReport {
+    /// The period covered, in nanoseconds since the epoch.
+    pub from: u64,
+    pub to: u64,
+    /// If more than 10,000 events were recorded over the requested period, the report stops short
+    /// of it, and this is the ID of the event to continue with, passed as `start_event`.
+    pub next: Option<u32>,
+    pub total: SpendingTotal,
+    /// Cycles sent to canisters, directly or by top-ups and cycles ledger deposits.
+    pub sent: SpendingTotal,
+    pub attached_to_calls: SpendingTotal,
+    pub canister_creation: SpendingTotal,
+    /// The groups are sorted by cycles, most first, and only the top 20 of each are kept.
+    pub by_canister: Vec<(Principal, SpendingTotal)>,
+    /// Sending cycles counts as calling `deposit_cycles`, and creating a canister as calling `create_canister`.
+    pub by_method: Vec<(String, SpendingTotal)>,
+    /// `None` for events recorded before callers were tracked.
+    pub by_caller: Vec<(Option<Principal>, SpendingTotal)>,
+    /// The address book name of the destination. `None` for unnamed destinations.
+    pub by_name: Vec<(Option<String>, SpendingTotal)>,
+}
+
+enum Category {
+    Sent,
+    Call,
+    Creation,
+}
+
+/// Where the cycles of an event went, through which method, and how many.
+fn spending(kind: &EventKind) -> Option<(Category, Principal, &str, u128)> {
+    match kind {
+        EventKind::CyclesSent {
+            to, amount, refund, ..
+        } => Some((
+            Category::Sent,
+            *to,
+            "deposit_cycles",
+            amount.saturating_sub(*refund),
+        )),
+        EventKind::CanisterToppedUp {
+            canister,
+            amount,
+            refund,
+            ..
+        } => Some((
+            Category::Sent,
+            *canister,
+            "deposit_cycles",
+            amount.saturating_sub(*refund),
+        )),
+        EventKind::CyclesLedgerDeposit { amount, refund, .. } => Some((
+            Category::Sent,
+            crate::cycles_ledger::id(),
+            "deposit",
+            amount.saturating_sub(*refund),
+        )),
+        EventKind::CanisterCalled {
+            canister,
+            method_name,
+            cycles,
+            refund,
+        } => Some((
+            Category::Call,
+            *canister,
+            method_name,
+            cycles.saturating_sub(refund.unwrap_or_default()),
+        )),
+        EventKind::CanisterCreated { canister, cycles } => {
+            Some((Category::Creation, *canister, "create_canister", *cycles))
+        }
+        _ => None,
+    }
+}
+
+/// Sort the groups of `totals` by cycles, most first, keeping the top [`MAX_GROUPS`].
+fn sorted<K>(totals: BTreeMap<K, SpendingTotal>) -> Vec<(K, SpendingTotal)> {
+    let mut totals: Vec<_> = totals.into_iter().collect();
+    totals.sort_by(|(_, a), (_, b)| b.cycles.cmp(&a.cycles));
+    totals.truncate(MAX_GROUPS);
+    totals
+}
+
+/// Break the spending of `events` down, naming destinations with `name_of`.
+fn report(
+    from: u64,
+    to: u64,
+    events: impl Iterator<Item = Event>,
+    name_of: impl Fn(&Principal) -> Option<String>,
+) -> SpendingReport {
+    let mut report = SpendingReport {
+        from,
+        to,
+        ..Default::default()
+    };
+    let mut by_canister = BTreeMap::new();
+    let mut by_method = BTreeMap::new();
+    let mut by_caller = BTreeMap::new();
+    let mut by_name = BTreeMap::new();
+    for event in events {
+        let Some((category, canister, method, cycles)) = spending(&event.kind) else {
+            continue;
+        };
+        report.total.add(cycles);
+        match category {
+            Category::Sent => &mut report.sent,
+            Category::Call => &mut report.attached_to_calls,
+            Category::Creation => &mut report.canister_creation,
+        }
+        .add(cycles);
+        by_canister
+            .entry(canister)
+            .or_insert_with(SpendingTotal::default)
+            .add(cycles);
+        by_method
+            .entry(method.to_string())
+            .or_insert_with(SpendingTotal::default)
+            .add(cycles);
+        by_caller
+            .entry(event.caller)
+            .or_insert_with(SpendingTotal::default)
+            .add(cycles);
+        by_name
+            .entry(name_of(&canister))
+            .or_insert_with(SpendingTotal::default)
+            .add(cycles);
+    }
+    report.by_canister = sorted(by_canister);
+    report.by_method = sorted(by_method);
+    report.by_caller = sorted(by_caller);
+    report.by_name = sorted(by_name);
+    report
+}
+
+/// The IDs of the events to look at, out of those from `first` up to `last`: at most
+/// [`MAX_SCANNED_EVENTS`], and none if `last` comes before `first`.
+fn scan_range(first: u32, last: u32) -> Range<u32> {
+    first..last.clamp(first, first.saturating_add(MAX_SCANNED_EVENTS))
+}
+
+/// The spending recorded in the event log between `from` and `to`, inclusive, starting at event
+/// `start_event` if given.
+///
+/// At most [`MAX_SCANNED_EVENTS`] events are looked at. If there are more, the report ends at the
+/// last of them, and its `next` is the `start_event` to continue with.
+pub fn get(from: u64, to: u64, start_event: Option<u32>) -> SpendingReport {
+    EVENT_BUFFER.with(|buffer| {
+        let buffer = buffer.borrow();
+        let first = buffer.first_id_at(from).max(start_event.unwrap_or(0));
+        let end = buffer.first_id_at(to.saturating_add(1));
+        let range = scan_range(first, end);
+        let next = (range.end < end).then_some(range.end);
+        // A report cut short only covers the time up to the last event in it.
+        let to = match next {
+            Some(next) => buffer
+                .iter_between(next as usize - 1..next as usize)
+                .next()
+                .map_or(to, |event| event.timestamp),
+            None => to,
+        };
+        ADDRESS_BOOK.with(|book| {
+            let book = book.borrow();
+            let events = buffer.iter_between(range.start as usize..range.end as usize);
+            SpendingReport {
+                next,
+                ..report(from, to, events, |canister| {
+                    book.find(canister).and_then(|entry| entry.name)
+                })
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{report, scan_range, SpendingTotal, MAX_SCANNED_EVENTS};
+    use crate::events::{Event, EventKind};
+    use candid::Principal;
+
+    fn event(caller: Principal, kind: EventKind) -> Event {
+        Event {
+            id: 0,
+            timestamp: 0,
+            kind,
+            caller: Some(caller),
+            method: None,
+            prev_hash: None,
+        }
+    }
+
+    #[test]
+    fn groups_spending() {
+        let alice = Principal::from_slice(&[1]);
+        let bob = Principal::from_slice(&[2]);
+        let canister = Principal::from_slice(&[3]);
+        let events = vec![
+            event(
+                alice,
+                EventKind::CyclesSent {
+                    to: canister,
+                    amount: 100,
+                    refund: 10,
+                    schedule: None,
+                    memo: None,
+                },
+            ),
+            event(
+                bob,
+                EventKind::CanisterCalled {
+                    canister,
+                    method_name: "greet".to_string(),
+                    cycles: 600,
+                    refund: Some(100),
+                },
+            ),
+            event(bob, EventKind::AddressRemoved { id: canister }),
+        ];
+        let report = report(0, 1, events.into_iter(), |_| Some("app".to_string()));
+
+        let total = |cycles, count| SpendingTotal { cycles, count };
+        assert_eq!(report.total, total(590, 2));
+        assert_eq!(report.sent, total(90, 1));
+        assert_eq!(report.attached_to_calls, total(500, 1));
+        assert_eq!(report.by_canister, vec![(canister, total(590, 2))]);
+        assert_eq!(
+            report.by_method,
+            vec![
+                ("greet".to_string(), total(500, 1)),
+                ("deposit_cycles".to_string(), total(90, 1))
+            ]
+        );
+        assert_eq!(
+            report.by_caller,
+            vec![(Some(bob), total(500, 1)), (Some(alice), total(90, 1))]
+        );
+        assert_eq!(
+            report.by_name,
+            vec![(Some("app".to_string()), total(590, 2))]
+        );
+    }
+
+    #[test]
+    fn keeps_the_top_groups() {
+        let alice = Principal::from_slice(&[1]);
+        let events = (1..=30u8).map(|i| {
+            event(
+                alice,
+                EventKind::CanisterCreated {
+                    canister: Principal::from_slice(&[i]),
+                    cycles: i as u128,
+                },
+            )
+        });
+        let report = report(0, 1, events, |_| None);
+
+        assert_eq!(
+            report.total,
+            SpendingTotal {
+                cycles: 465,
+                count: 30
+            }
+        );
+        assert_eq!(report.by_canister.len(), 20);
+        assert_eq!(report.by_canister[0].0, Principal::from_slice(&[30]));
+        assert_eq!(report.by_canister[19].0, Principal::from_slice(&[11]));
+    }
+
+    #[test]
+    fn bounds_the_scan() {
+        assert_eq!(scan_range(5, 10), 5..10);
+        // A window whose end comes before its start.
+        assert!(scan_range(10, 5).is_empty());
+        assert_eq!(scan_range(1, u32::MAX).len(), MAX_SCANNED_EVENTS as usize);
+    }
+}
@@ -0,0 +1,138 @@
+use crate::events::MANAGED_LIST;
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api;
+use ic_cdk::api::call::RejectionCode;
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+/// Whether a canister is running, as reported by the management canister.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub enum CanisterRunStatus {
+    #[serde(rename = "running")]
+    Running,
+    #[serde(rename = "stopping")]
+    Stopping,
+    #[serde(rename = "stopped")]
+    Stopped,
+}
+
+/// The state of a canister, as reported by the management canister's `canister_status`.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct CanisterStatus {
+    pub status: CanisterRunStatus,
+    pub cycles: u128,
+    pub memory_size: u128,
+    pub module_hash: Option<ByteBuf>,
+    /// `None` if the subnet doesn't report it.
+    pub idle_cycles_burned_per_day: Option<u128>,
+    pub controllers: Vec<Principal>,
+}
+
+/// The last known state of a managed canister.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct ManagedCanisterStatus {
+    pub canister: Principal,
+    /// The status from the last successful refresh.
+    pub status: Option<CanisterStatus>,
+    /// When `status` was fetched.
+    pub updated_at: Option<u64>,
+    /// The error of the last refresh, if it failed. The wallet can only see canisters it controls.
+    pub error: Option<String>,
+}
+
+thread_local! {
+    /// The last known state of each managed canister. Not kept across upgrades.
+    pub static STATUSES: RefCell<BTreeMap<Principal, ManagedCanisterStatus>> = Default::default();
+}
+
+#[derive(CandidType)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+#[derive(Deserialize, CandidType)]
+struct DefiniteCanisterSettings {
+    controllers: Vec<Principal>,
+}
+
+#[derive(Deserialize, CandidType)]
+struct CanisterStatusResponse {
+    status: CanisterRunStatus,
+    settings: DefiniteCanisterSettings,
+    module_hash: Option<ByteBuf>,
+    memory_size: Nat,
+    cycles: Nat,
+    idle_cycles_burned_per_day: Option<Nat>,
+}
+
+fn nat_to_u128(nat: Nat) -> u128 {
+    u128::try_from(nat.0).unwrap_or(u128::MAX)
+}
+
+/// Fetch the status of a canister from the management canister, and remember it.
+pub async fn fetch(canister: Principal) -> Result<CanisterStatus, String> {
+    let result = api::call::call(
+        Principal::management_canister(),
+        "canister_status",
+        (CanisterIdRecord {
+            canister_id: canister,
+        },),
+    )
+    .await
+    .map(|(response,): (CanisterStatusResponse,)| CanisterStatus {
+        status: response.status,
+        cycles: nat_to_u128(response.cycles),
+        memory_size: nat_to_u128(response.memory_size),
+        module_hash: response.module_hash,
+        idle_cycles_burned_per_day: response.idle_cycles_burned_per_day.map(nat_to_u128),
+        controllers: response.settings.controllers,
+    });
+    // Only controllers can get the status of a canister.
+    let controlled = match &result {
+        Ok(status) => Some(status.controllers.contains(&api::id())),
+        Err((RejectionCode::SysTransient, _)) => None,
+        Err(_) => Some(false),
+    };
+    if let Some(controlled) = controlled {
+        MANAGED_LIST.with(|list| list.borrow_mut().set_controlled(&canister, controlled));
+    }
+    let result = result.map_err(|(code, msg)| {
+        format!(
+            "Could not get the status of the canister: {}: {}",
+            code as u8, msg
+        )
+    });
+    STATUSES.with(|statuses| {
+        let mut statuses = statuses.borrow_mut();
+        let entry = statuses
+            .entry(canister)
+            .or_insert_with(|| ManagedCanisterStatus {
+                canister,
+                status: None,
+                updated_at: None,
+                error: None,
+            });
+        match &result {
+            Ok(status) => {
+                entry.status = Some(status.clone());
+                entry.updated_at = Some(api::time());
+                entry.error = None;
+            }
+            Err(err) => entry.error = Some(err.clone()),
+        }
+    });
+    result
+}
+
+/// The last known state of the given canisters, in order; unknown canisters are skipped.
+pub fn get(canisters: impl Iterator<Item = Principal>) -> Vec<ManagedCanisterStatus> {
+    STATUSES.with(|statuses| {
+        let statuses = statuses.borrow();
+        canisters
+            .filter_map(|canister| statuses.get(&canister).cloned())
+            .collect()
+    })
+}
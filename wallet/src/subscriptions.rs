@@ -0,0 +1,372 @@
+use crate::events::{Event, EVENT_BUFFER};
+use candid::{CandidType, Principal};
+use ic_cdk::api;
+use serde::Deserialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+/// How often pending deliveries are attempted.
+const DELIVERY_INTERVAL: Duration = Duration::from_secs(30);
+/// The most deliveries attempted in one run.
+const MAX_DELIVERIES_PER_RUN: usize = 50;
+/// The most deliveries of a single subscription attempted in one run, so one subscription's backlog
+/// doesn't hold up the others.
+const MAX_DELIVERIES_PER_SUBSCRIPTION: usize = 10;
+/// The most deliveries of a single subscription waiting at the same time. Deliveries past this are dropped.
+const MAX_PENDING_PER_SUBSCRIPTION: usize = 100;
+/// The most subscriptions the wallet can have.
+const MAX_SUBSCRIPTIONS: usize = 20;
+/// The attempts after which a delivery is given up on.
+const MAX_ATTEMPTS: u32 = 10;
+/// The delay before the first retry, in nanoseconds. It doubles with each attempt.
+const RETRY_DELAY: u64 = 30 * 1_000_000_000;
+/// The longest delay between two retries, in nanoseconds.
+const MAX_RETRY_DELAY: u64 = 60 * 60 * 1_000_000_000;
+/// The longest method name a subscription can have.
+const MAX_METHOD_LENGTH: usize = 256;
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SubscribeArgs {
+    pub canister: Principal,
+    /// The method called with each event, as a single `Event128`. Its reply is ignored.
+    pub method: String,
+    /// The names of the kinds of events to deliver, as used by event filters. Empty for every kind.
+    pub kinds: Vec<String>,
+}
+
+/// A canister method to deliver events to.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct Subscription {
+    pub id: u64,
+    pub canister: Principal,
+    pub method: String,
+    pub kinds: Vec<String>,
+}
+
+impl Subscription {
+    fn wants(&self, kind: &str) -> bool {
+        self.kinds.is_empty() || self.kinds.iter().any(|wanted| wanted == kind)
+    }
+}
+
+/// An event that still has to be delivered to a subscription.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct PendingDelivery {
+    pub subscription: u64,
+    pub event_id: u32,
+    pub attempts: u32,
+    /// When the delivery is next attempted, in nanoseconds since the epoch.
+    pub next_attempt: u64,
+    /// The error of the last attempt, if any.
+    pub last_error: Option<String>,
+}
+
+/// A subscription and how its deliveries went, as reported to the wallet API.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct SubscriptionStatus {
+    pub subscription: Subscription,
+    pub delivered: u64,
+    /// Deliveries given up on after too many attempts.
+    pub failed: u64,
+    /// Deliveries dropped because too many of this subscription's were pending.
+    pub dropped: u64,
+    pub pending: u32,
+    /// The ID of the last event delivered.
+    pub last_delivered: Option<u32>,
+    /// The error of the last failed attempt.
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+struct Stats {
+    delivered: u64,
+    failed: u64,
+    dropped: u64,
+    last_delivered: Option<u32>,
+    last_error: Option<String>,
+}
+
+/// The subscriptions of the wallet and the deliveries they are waiting for.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct Subscriptions {
+    next_id: u64,
+    subscriptions: BTreeMap<u64, (Subscription, Stats)>,
+    pending: VecDeque<PendingDelivery>,
+}
+
+thread_local! {
+    pub static SUBSCRIPTIONS: RefCell<Subscriptions> = Default::default();
+}
+
+/// The delay before attempting a delivery again, after `attempts` failed attempts.
+fn retry_delay(attempts: u32) -> u64 {
+    RETRY_DELAY
+        .saturating_mul(1 << attempts.saturating_sub(1).min(16))
+        .min(MAX_RETRY_DELAY)
+}
+
+impl Subscriptions {
+    pub fn add(&mut self, args: SubscribeArgs) -> Result<u64, String> {
+        if self.subscriptions.len() >= MAX_SUBSCRIPTIONS {
+            return Err(format!(
+                "A wallet cannot have more than {} subscriptions.",
+                MAX_SUBSCRIPTIONS
+            ));
+        }
+        if args.method.is_empty() || args.method.len() > MAX_METHOD_LENGTH {
+            return Err(format!(
+                "The method name must be between 1 and {} bytes long.",
+                MAX_METHOD_LENGTH
+            ));
+        }
+        let id = self.next_id;
+        self.next_id += 1;
+        let subscription = Subscription {
+            id,
+            canister: args.canister,
+            method: args.method,
+            kinds: args.kinds,
+        };
+        self.subscriptions
+            .insert(id, (subscription, Stats::default()));
+        Ok(id)
+    }
+
+    /// Remove a subscription, along with its pending deliveries.
+    pub fn remove(&mut self, id: u64) -> Result<(), String> {
+        self.subscriptions
+            .remove(&id)
+            .ok_or_else(|| format!("No subscription with ID {}.", id))?;
+        self.pending.retain(|delivery| delivery.subscription != id);
+        Ok(())
+    }
+
+    /// Queue the event `event_id`, of kind `kind`, for the subscriptions that want it.
+    pub fn enqueue(&mut self, event_id: u32, kind: &str, now: u64) {
+        for (subscription, stats) in self.subscriptions.values_mut() {
+            if !subscription.wants(kind) {
+                continue;
+            }
+            let pending = self
+                .pending
+                .iter()
+                .filter(|delivery| delivery.subscription == subscription.id)
+                .count();
+            if pending >= MAX_PENDING_PER_SUBSCRIPTION {
+                stats.dropped += 1;
+                continue;
+            }
+            self.pending.push_back(PendingDelivery {
+                subscription: subscription.id,
+                event_id,
+                attempts: 0,
+                next_attempt: now,
+                last_error: None,
+            });
+        }
+    }
+
+    /// The deliveries to attempt at `now`, oldest first, as `(subscription, event ID)`.
+    fn due(&self, now: u64) -> Vec<(Subscription, u32)> {
+        let mut per_subscription = BTreeMap::<u64, usize>::new();
+        let mut due = Vec::new();
+        for delivery in &self.pending {
+            if due.len() >= MAX_DELIVERIES_PER_RUN {
+                break;
+            }
+            if delivery.next_attempt > now {
+                continue;
+            }
+            let Some((subscription, _)) = self.subscriptions.get(&delivery.subscription) else {
+                continue;
+            };
+            let count = per_subscription.entry(subscription.id).or_default();
+            if *count >= MAX_DELIVERIES_PER_SUBSCRIPTION {
+                continue;
+            }
+            *count += 1;
+            due.push((subscription.clone(), delivery.event_id));
+        }
+        due
+    }
+
+    /// Record the outcome of an attempt, retrying it later if it failed.
+    fn complete(&mut self, subscription: u64, event_id: u32, result: Result<(), String>, now: u64) {
+        let Some(index) = self.pending.iter().position(|delivery| {
+            delivery.subscription == subscription && delivery.event_id == event_id
+        }) else {
+            return;
+        };
+        let Some((_, stats)) = self.subscriptions.get_mut(&subscription) else {
+            return;
+        };
+        match result {
+            Ok(()) => {
+                self.pending.remove(index);
+                stats.delivered += 1;
+                stats.last_delivered = Some(event_id);
+            }
+            Err(err) => {
+                stats.last_error = Some(err.clone());
+                let delivery = &mut self.pending[index];
+                delivery.attempts += 1;
+                delivery.last_error = Some(err);
+                if delivery.attempts >= MAX_ATTEMPTS {
+                    self.pending.remove(index);
+                    stats.failed += 1;
+                } else {
+                    delivery.next_attempt = now.saturating_add(retry_delay(delivery.attempts));
+                }
+            }
+        }
+    }
+
+    pub fn statuses(&self) -> Vec<SubscriptionStatus> {
+        self.subscriptions
+            .values()
+            .map(|(subscription, stats)| SubscriptionStatus {
+                subscription: subscription.clone(),
+                delivered: stats.delivered,
+                failed: stats.failed,
+                dropped: stats.dropped,
+                pending: self
+                    .pending
+                    .iter()
+                    .filter(|delivery| delivery.subscription == subscription.id)
+                    .count() as u32,
+                last_delivered: stats.last_delivered,
+                last_error: stats.last_error.clone(),
+            })
+            .collect()
+    }
+
+    /// The pending deliveries, of a single subscription if given.
+    pub fn pending(&self, subscription: Option<u64>) -> Vec<PendingDelivery> {
+        self.pending
+            .iter()
+            .filter(|delivery| subscription.map_or(true, |id| delivery.subscription == id))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Queue a newly recorded event for the subscriptions that want it.
+pub fn enqueue(event_id: u32, kind: &str) {
+    SUBSCRIPTIONS.with(|subscriptions| {
+        subscriptions
+            .borrow_mut()
+            .enqueue(event_id, kind, api::time())
+    });
+}
+
+/// Start delivering the pending events periodically.
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(DELIVERY_INTERVAL, deliver_due);
+}
+
+/// Attempt the deliveries that are due.
+fn deliver_due() {
+    let due = SUBSCRIPTIONS.with(|subscriptions| subscriptions.borrow().due(api::time()));
+    for (subscription, event_id) in due {
+        let result = deliver(&subscription, event_id);
+        SUBSCRIPTIONS.with(|subscriptions| {
+            subscriptions
+                .borrow_mut()
+                .complete(subscription.id, event_id, result, api::time())
+        });
+    }
+}
+
+/// Notify the subscription's method of the event, with a one-way call. The delivery counts once the
+/// call is made; only calls that can't be made at all are retried.
+fn deliver(subscription: &Subscription, event_id: u32) -> Result<(), String> {
+    let event: Option<Event> = EVENT_BUFFER.with(|buffer| {
+        buffer
+            .borrow()
+            .iter_between(event_id as usize..event_id as usize + 1)
+            .next()
+    });
+    // The event is gone, so there is nothing left to deliver.
+    let Some(event) = event else {
+        return Ok(());
+    };
+    let args = candid::encode_one(event).map_err(|err| err.to_string())?;
+    api::call::notify_raw(subscription.canister, &subscription.method, &args, 0)
+        .map_err(|code| format!("Could not send the event to the subscriber: {}", code as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        retry_delay, SubscribeArgs, Subscriptions, MAX_ATTEMPTS, MAX_DELIVERIES_PER_SUBSCRIPTION,
+        MAX_PENDING_PER_SUBSCRIPTION, RETRY_DELAY,
+    };
+    use candid::Principal;
+
+    #[test]
+    fn retries_failed_deliveries() {
+        let mut subscriptions = Subscriptions::default();
+        let id = subscriptions
+            .add(SubscribeArgs {
+                canister: Principal::anonymous(),
+                method: "on_event".to_string(),
+                kinds: vec!["CyclesReceived".to_string()],
+            })
+            .unwrap();
+        subscriptions.enqueue(0, "CyclesSent", 0);
+        subscriptions.enqueue(1, "CyclesReceived", 0);
+        subscriptions.enqueue(2, "CyclesReceived", 0);
+        let due = subscriptions.due(0);
+        assert_eq!(
+            due.iter().map(|(_, event)| *event).collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        subscriptions.complete(id, 1, Ok(()), 0);
+        subscriptions.complete(id, 2, Err("busy".to_string()), 0);
+        assert!(subscriptions.due(RETRY_DELAY - 1).is_empty());
+        assert_eq!(subscriptions.due(RETRY_DELAY).len(), 1);
+        assert_eq!(retry_delay(2), 2 * RETRY_DELAY);
+
+        for _ in 1..MAX_ATTEMPTS {
+            subscriptions.complete(id, 2, Err("busy".to_string()), 0);
+        }
+        let status = &subscriptions.statuses()[0];
+        assert_eq!((status.delivered, status.failed, status.pending), (1, 1, 0));
+        assert_eq!(status.last_delivered, Some(1));
+    }
+
+    #[test]
+    fn limits_each_subscription() {
+        let mut subscriptions = Subscriptions::default();
+        let subscribe = |subscriptions: &mut Subscriptions| {
+            subscriptions
+                .add(SubscribeArgs {
+                    canister: Principal::anonymous(),
+                    method: "on_event".to_string(),
+                    kinds: vec![],
+                })
+                .unwrap()
+        };
+        let busy = subscribe(&mut subscriptions);
+        for event_id in 0..MAX_PENDING_PER_SUBSCRIPTION as u32 + 5 {
+            subscriptions.enqueue(event_id, "CyclesSent", 0);
+        }
+        let quiet = subscribe(&mut subscriptions);
+        subscriptions.enqueue(1000, "CyclesSent", 0);
+
+        // The busy subscription's backlog doesn't crowd out the other one.
+        let statuses = subscriptions.statuses();
+        assert_eq!(statuses[0].pending as usize, MAX_PENDING_PER_SUBSCRIPTION);
+        assert_eq!(statuses[0].dropped, 6);
+        assert_eq!(statuses[1].pending, 1);
+
+        let due = subscriptions.due(0);
+        assert_eq!(due.len(), MAX_DELIVERIES_PER_SUBSCRIPTION + 1);
+        assert!(due[..MAX_DELIVERIES_PER_SUBSCRIPTION]
+            .iter()
+            .all(|(subscription, _)| subscription.id == busy));
+        assert_eq!((due[10].0.id, due[10].1), (quiet, 1000));
+    }
+}
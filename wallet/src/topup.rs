@@ -0,0 +1,250 @@
+use crate::events::{self, EventKind};
+use crate::status;
+use candid::{CandidType, Principal};
+use ic_cdk::api;
+use serde::Deserialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+
+const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+/// How often managed canisters with a top-up policy are checked.
+const CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// The name events recorded by the top-up timer are attributed to.
+const TIMER_METHOD: &str = "timer:top_up";
+
+/// How the wallet keeps a managed canister funded.
+#[derive(Clone, Debug, PartialEq, Eq, CandidType, Deserialize)]
+pub struct TopUpPolicy {
+    /// Top the canister up once its balance falls below this many cycles.
+    pub min_balance: u128,
+    /// The balance to top the canister up to.
+    pub target_balance: u128,
+    /// The most cycles to send to the canister over any rolling 24 hours.
+    pub max_cycles_per_day: u128,
+}
+
+/// A top-up policy along with what the timer last did with it, as reported to the wallet API.
+#[derive(Clone, Debug, CandidType, Deserialize)]
+pub struct TopUpStatus {
+    pub canister: Principal,
+    pub policy: TopUpPolicy,
+    pub sent_last_day: u128,
+    /// When the canister's balance was last checked.
+    pub last_checked: Option<u64>,
+    /// The error of the last check or top-up, if it failed.
+    pub last_error: Option<String>,
+}
+
+#[derive(Clone, Debug, CandidType, Deserialize)]
+struct TopUp {
+    policy: TopUpPolicy,
+    /// The timestamp and amount of each top-up in the last 24 hours.
+    sent: VecDeque<(u64, u128)>,
+    last_checked: Option<u64>,
+    last_error: Option<String>,
+}
+
+impl TopUp {
+    /// The cycles sent in the 24 hours before `now`.
+    fn sent_since(&self, now: u64) -> u128 {
+        let cutoff = now.saturating_sub(DAY_NANOS);
+        self.sent
+            .iter()
+            .filter(|(timestamp, _)| *timestamp > cutoff)
+            .map(|(_, amount)| amount)
+            .sum()
+    }
+
+    /// Forget the top-ups from more than 24 hours before `now`.
+    fn prune(&mut self, now: u64) {
+        let cutoff = now.saturating_sub(DAY_NANOS);
+        while matches!(self.sent.front(), Some((timestamp, _)) if *timestamp <= cutoff) {
+            self.sent.pop_front();
+        }
+    }
+
+    /// The number of cycles to send to a canister with `balance` cycles, if any.
+    fn amount_for(&self, balance: u128, now: u64) -> Option<u128> {
+        if balance >= self.policy.min_balance {
+            return None;
+        }
+        let budget = self
+            .policy
+            .max_cycles_per_day
+            .saturating_sub(self.sent_since(now));
+        let amount = self
+            .policy
+            .target_balance
+            .saturating_sub(balance)
+            .min(budget);
+        (amount > 0).then_some(amount)
+    }
+}
+
+/// The top-up policies of the managed canisters.
+#[derive(Clone, Debug, Default, CandidType, Deserialize)]
+pub struct TopUps {
+    top_ups: BTreeMap<Principal, TopUp>,
+}
+
+thread_local! {
+    pub static TOP_UPS: RefCell<TopUps> = Default::default();
+    /// Whether the timer is still going through the canisters, so runs don't overlap.
+    static RUNNING: Cell<bool> = Cell::new(false);
+}
+
+impl TopUps {
+    pub fn set(&mut self, canister: Principal, policy: Option<TopUpPolicy>) {
+        match policy {
+            Some(policy) => {
+                self.top_ups
+                    .entry(canister)
+                    .and_modify(|top_up| top_up.policy = policy.clone())
+                    .or_insert(TopUp {
+                        policy,
+                        sent: VecDeque::new(),
+                        last_checked: None,
+                        last_error: None,
+                    });
+            }
+            None => {
+                self.top_ups.remove(&canister);
+            }
+        }
+    }
+
+    pub fn statuses(&self, now: u64) -> Vec<TopUpStatus> {
+        self.top_ups
+            .iter()
+            .map(|(canister, top_up)| TopUpStatus {
+                canister: *canister,
+                policy: top_up.policy.clone(),
+                sent_last_day: top_up.sent_since(now),
+                last_checked: top_up.last_checked,
+                last_error: top_up.last_error.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Check that a policy makes sense.
+pub fn validate(policy: &TopUpPolicy) -> Result<(), String> {
+    if policy.target_balance < policy.min_balance {
+        Err("The target balance cannot be below the minimum balance.".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// Start checking the canisters with a top-up policy periodically.
+pub fn start_timer() {
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || ic_cdk::spawn(top_up_all()));
+}
+
+/// Clears [`RUNNING`] when a run ends, even if one of its calls trapped.
+struct RunGuard;
+
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        RUNNING.with(|running| running.set(false));
+    }
+}
+
+async fn top_up_all() {
+    if RUNNING.with(|running| running.replace(true)) {
+        return;
+    }
+    let _guard = RunGuard;
+    let canisters: Vec<Principal> =
+        TOP_UPS.with(|top_ups| top_ups.borrow().top_ups.keys().copied().collect());
+    for canister in canisters {
+        let result = top_up(canister).await;
+        TOP_UPS.with(|top_ups| {
+            if let Some(top_up) = top_ups.borrow_mut().top_ups.get_mut(&canister) {
+                top_up.prune(api::time());
+                top_up.last_checked = Some(api::time());
+                top_up.last_error = result.err();
+            }
+        });
+    }
+}
+
+#[derive(CandidType)]
+struct CanisterIdRecord {
+    canister_id: Principal,
+}
+
+async fn top_up(canister: Principal) -> Result<(), String> {
+    let balance = status::fetch(canister).await?.cycles;
+    let amount = match TOP_UPS.with(|top_ups| {
+        top_ups
+            .borrow()
+            .top_ups
+            .get(&canister)
+            .and_then(|top_up| top_up.amount_for(balance, api::time()))
+    }) {
+        Some(amount) => amount,
+        None => return Ok(()),
+    };
+    let result: Result<(), _> = api::call::call_with_payment128(
+        Principal::management_canister(),
+        "deposit_cycles",
+        (CanisterIdRecord {
+            canister_id: canister,
+        },),
+        amount,
+    )
+    .await;
+    let refund = api::call::msg_cycles_refunded128();
+    TOP_UPS.with(|top_ups| {
+        if let Some(top_up) = top_ups.borrow_mut().top_ups.get_mut(&canister) {
+            top_up
+                .sent
+                .push_back((api::time(), amount.saturating_sub(refund)));
+        }
+    });
+    events::record_from(
+        api::id(),
+        TIMER_METHOD,
+        EventKind::CanisterToppedUp {
+            canister,
+            amount,
+            refund,
+            balance,
+        },
+    );
+    crate::update_chart();
+    result.map_err(|(code, msg)| {
+        format!("An error happened during the call: {}: {}", code as u8, msg)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{TopUp, TopUpPolicy, DAY_NANOS};
+    use std::collections::VecDeque;
+
+    #[test]
+    fn tops_up_within_daily_budget() {
+        let mut top_up = TopUp {
+            policy: TopUpPolicy {
+                min_balance: 100,
+                target_balance: 500,
+                max_cycles_per_day: 600,
+            },
+            sent: VecDeque::new(),
+            last_checked: None,
+            last_error: None,
+        };
+        assert_eq!(top_up.amount_for(100, DAY_NANOS), None);
+        assert_eq!(top_up.amount_for(50, DAY_NANOS), Some(450));
+        top_up.sent.push_back((DAY_NANOS, 450));
+        assert_eq!(top_up.amount_for(0, DAY_NANOS + 1), Some(150));
+        assert_eq!(top_up.sent_since(2 * DAY_NANOS), 0);
+        assert_eq!(top_up.sent.len(), 1);
+        assert_eq!(top_up.amount_for(0, 2 * DAY_NANOS), Some(500));
+        top_up.prune(2 * DAY_NANOS);
+        assert!(top_up.sent.is_empty());
+    }
+}